@@ -0,0 +1,148 @@
+//! The alt text of an image, looked for in order in
+//!
+//! - a sidecar file next to the image, `my_image.alt` or `my_image.txt`;
+//! - a CSV mapping of `filename,alt text` rows, given with `--alt-csv` or `alt_csv`;
+//! - the embedded metadata: EXIF ImageDescription, XMP dc:description or IPTC caption.
+//!
+//! An image with none gets an empty `alt=""`, marking it decorative, and a warning.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::metadata::xmp_packet;
+use crate::opts::Opts;
+use crate::utils::file_stem;
+
+/// The sidecar extensions, in the order tried.
+const SIDECARS: [&str; 2] = ["alt", "txt"];
+
+/// Alt text by file, from a CSV of `filename,alt text` rows. The filename can be the path
+/// relative to the input path, the file name or the name without extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AltMap {
+    path: PathBuf,
+    entries: BTreeMap<String, String>,
+}
+
+impl AltMap {
+    /// Read the CSV; a row with fewer than two fields is ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AltMap> {
+        let path = path.as_ref();
+        let invalid = |e: csv::Error| Error::Config(path.to_path_buf(), e.to_string());
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(invalid)?;
+
+        let mut entries = BTreeMap::new();
+        for record in reader.records() {
+            let record = record.map_err(invalid)?;
+            if let (Some(file), Some(alt)) = (record.get(0), record.get(1)) {
+                entries.insert(file.to_string(), alt.to_string());
+            }
+        }
+        Ok(AltMap { path: path.to_path_buf(), entries })
+    }
+
+    /// The path of the CSV.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The alt text of the image, by its path relative to the input, its file name or its stem.
+    pub fn get(&self, path: &Path, opts: &Opts) -> Option<&str> {
+        let rel = path.strip_prefix(&opts.inpath).ok().and_then(Path::to_str);
+        let name = path.file_name().and_then(|n| n.to_str());
+        let stem = file_stem(path).ok();
+
+        [rel, name, stem].into_iter().flatten()
+            .find_map(|key| self.entries.get(key))
+            .map(String::as_str)
+    }
+}
+
+/// The alt text of the image, or `None` if there is none to be found.
+pub fn alt_text(path: &Path, opts: &Opts) -> Option<String> {
+    let text = sidecar(path)
+        .or_else(|| opts.alts.as_ref().and_then(|alts| alts.get(path, opts)).map(str::to_string))
+        .or_else(|| exif_description(path))
+        .or_else(|| embedded_description(path))?;
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.is_empty() {
+        true => None,
+        false => Some(text),
+    }
+}
+
+/// The text of `my_image.alt` or `my_image.txt`.
+fn sidecar(path: &Path) -> Option<String> {
+    SIDECARS.iter()
+        .map(|ext| path.with_extension(ext))
+        .find_map(|p| std::fs::read_to_string(p).ok())
+}
+
+/// The EXIF ImageDescription.
+fn exif_description(path: &Path) -> Option<String> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    match &exif.get_field(exif::Tag::ImageDescription, exif::In::PRIMARY)?.value {
+        exif::Value::Ascii(parts) => parts.first().map(|p| String::from_utf8_lossy(p).trim_end_matches('\0').to_string()),
+        _ => None,
+    }
+}
+
+/// The XMP dc:description or else the IPTC caption, both stored uncompressed within the file.
+fn embedded_description(path: &Path) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+    xmp_description(&bytes).or_else(|| iptc_caption(&bytes))
+}
+
+/// The default language dc:description of an XMP packet.
+fn xmp_description(bytes: &[u8]) -> Option<String> {
+    lazy_static::lazy_static! {
+        static ref ALT: regex::bytes::Regex = regex::bytes::Regex::new(
+            r#"(?s)<dc:description>.*?<rdf:li[^>]*>(.*?)</rdf:li>"#).unwrap();
+        static ref ATTR: regex::bytes::Regex = regex::bytes::Regex::new(
+            r#"dc:description="([^"]*)""#).unwrap();
+    }
+
+    let packet = xmp_packet(bytes)?;
+    let text = ALT.captures(packet).or_else(|| ATTR.captures(packet))?.get(1)?.as_bytes();
+    Some(unescape_xml(&String::from_utf8_lossy(text)))
+}
+
+/// The IPTC Caption/Abstract, dataset 2:120, within a JPEG APP13 segment.
+fn iptc_caption(bytes: &[u8]) -> Option<String> {
+    let app13 = jpeg_segments(bytes).find(|(marker, _)| *marker == 0xED)?.1;
+
+    let at = app13.windows(3).position(|w| w == [0x1C, 0x02, 0x78])?;
+    let len = u16::from_be_bytes([*app13.get(at + 3)?, *app13.get(at + 4)?]) as usize;
+    let text = app13.get(at + 5..at + 5 + len)?;
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+/// The marker and payload of each JPEG segment before the image data; none for other formats.
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut at = if bytes.starts_with(&[0xFF, 0xD8]) { 2 } else { bytes.len() };
+    std::iter::from_fn(move || {
+        let marker = *bytes.get(at..at + 2).filter(|m| m[0] == 0xFF)?.get(1)?;
+        if marker == 0xDA {
+            return None;
+        }
+        let len = u16::from_be_bytes([*bytes.get(at + 2)?, *bytes.get(at + 3)?]) as usize;
+        let payload = bytes.get(at + 4..at + 2 + len)?;
+        at += 2 + len;
+        Some((marker, payload))
+    })
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
@@ -0,0 +1,169 @@
+//! A content-hash cache of the work already done, kept as an index file under the outpath.
+//!
+//! Each source has one entry, holding a hash of its bytes together with the effective options and
+//! the outputs they produced relative to the outpath. Unlike the mtimes used by an incremental
+//! run the key survives a fresh checkout, so the outpath and its index can be restored from a CI
+//! cache artifact on another machine.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::opts::Opts;
+use crate::crop::{given_focal_point, Crop, FocalPoint};
+use crate::icc::IccPolicy;
+use crate::metadata::Policy;
+use crate::utils::mk_dir;
+
+/// The name of the index file within the outpath.
+pub const CACHE_FILE: &str = ".srcset-cache.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    /// The last outputs of each source by its path relative to the inpath, so that a source
+    /// changed many times still has the one entry.
+    #[serde(default)]
+    sources: BTreeMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// The hash of the content and options the outputs were produced from.
+    hash: String,
+    /// Output paths relative to the outpath.
+    outputs: Vec<PathBuf>,
+}
+
+/// Every option that affects the outputs of a source, hashed along with its content.
+#[derive(Serialize)]
+struct Settings<'a> {
+    sizes: &'a [u32],
+    density: Option<u32>,
+    exts: &'a [&'a str],
+    dir: &'a Path,
+    prefix: &'a str,
+    sizes_attr: &'a Option<String>,
+    template: &'a str,
+    metadata: Policy,
+    icc: IccPolicy,
+    crops: &'a [Crop],
+    focus: Option<FocalPoint>,
+    quality: u8,
+    is_lossless: bool,
+    avif_speed: u8,
+    max_bytes: Option<u64>,
+    max_bytes_sizes: &'a BTreeMap<u32, u64>,
+    ssim: Option<f64>,
+    filter: String,
+    sigma: f32,
+    thresh: i32,
+    is_sharpen: bool,
+    is_sharpen_by_ratio: bool,
+    unsharpen_sizes: &'a BTreeMap<u32, (f32, i32)>,
+    is_hashed: bool,
+    use_largest: bool,
+    is_tagfile: bool,
+}
+
+/// The entry of a source in the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    source: PathBuf,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    root: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl Cache {
+    /// Load the index from the outpath, or start an empty one if there is none.
+    pub fn load(outpath: &Path) -> Result<Cache> {
+        let path = outpath.join(CACHE_FILE);
+        let index = match File::open(&path) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Cache { path, root: outpath.to_path_buf(), index: Mutex::new(index) })
+    }
+
+    /// Write the index back to the outpath.
+    pub fn save(&self) -> Result<()> {
+        mk_dir(&self.path);
+        let fout = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(fout, &*self.index.lock().unwrap())?;
+        Ok(())
+    }
+
+    /// The key for a source: a hash of its content and of every option that affects the outputs.
+    pub fn key(source: &Path, opts: &Opts, sizes: &[u32], exts: &[&str], dir: &Path) -> Result<Key> {
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut File::open(source)?, &mut hasher)?;
+
+        let settings = Settings {
+            sizes,
+            density: opts.density,
+            exts,
+            dir: dir.strip_prefix(&opts.outpath).unwrap_or(dir),
+            prefix: &opts.prefix,
+            sizes_attr: &opts.sizes_attr,
+            template: opts.template.source(),
+            metadata: opts.metadata,
+            icc: opts.icc,
+            crops: &opts.crops,
+            focus: given_focal_point(source, opts)?,
+            quality: opts.quality,
+            is_lossless: opts.is_lossless,
+            avif_speed: opts.avif_speed,
+            max_bytes: opts.max_bytes,
+            max_bytes_sizes: &opts.max_bytes_sizes,
+            ssim: opts.ssim,
+            filter: format!("{:?}", opts.filter),
+            sigma: opts.sigma,
+            thresh: opts.thresh,
+            is_sharpen: opts.is_sharpen,
+            is_sharpen_by_ratio: opts.is_sharpen_by_ratio,
+            unsharpen_sizes: &opts.unsharpen_sizes,
+            is_hashed: opts.is_hashed,
+            use_largest: opts.use_largest,
+            is_tagfile: opts.is_tagfile,
+        };
+        hasher.update(&serde_json::to_vec(&settings)?);
+
+        // A single file given as the inpath is known by its name
+        let source_name = match source.strip_prefix(&opts.inpath) {
+            Ok(rel) if !rel.as_os_str().is_empty() => rel.to_path_buf(),
+            _ => source.file_name().map(PathBuf::from).unwrap_or_else(|| source.to_path_buf()),
+        };
+        Ok(Key { source: source_name, hash: hasher.finalize().to_hex().to_string() })
+    }
+
+    /// Whether `key` was produced before and each of its outputs is still on disk.
+    pub fn is_done(&self, key: &Key, outputs: &[&Path]) -> bool {
+        let index = self.index.lock().unwrap();
+        match index.sources.get(&key.source) {
+            Some(done) if done.hash == key.hash => outputs.iter().all(|o| {
+                let rel = o.strip_prefix(&self.root).unwrap_or(o);
+                done.outputs.iter().any(|d| d == rel) && o.is_file()
+            }),
+            _ => false,
+        }
+    }
+
+    /// Record the outputs produced for `key`, in place of any earlier ones of its source.
+    pub fn insert(&self, key: Key, outputs: &[&Path]) {
+        let outputs = outputs.iter()
+            .map(|o| o.strip_prefix(&self.root).unwrap_or(o).to_path_buf())
+            .collect();
+        self.index.lock().unwrap().sources.insert(key.source, Entry { hash: key.hash, outputs });
+    }
+}
@@ -0,0 +1,220 @@
+//! The `srcset.toml` configuration file.
+//!
+//! A `srcset.toml` in the input root sets the options for every image, and one in a subdirectory
+//! overrides its parents for the images under it. Options given on the command line, or set on the
+//! `OptsBuilder`, override every file. For example
+//!
+//! ```toml
+//! sizes = [480, 960, 1920]
+//! sizes_attr = "(min-width: 1024px) 50vw, 100vw"
+//! formats = ["avif", "webp", "jpg"]
+//! quality = 75
+//! lossless = false
+//! avif_speed = 6
+//! max_bytes = "80k"
+//! ssim = 0.98
+//! filter = "catmullrom"
+//! unsharpen = "0.25,8"
+//! sharpen_by_ratio = true
+//! prefix = "/images"
+//! nested = true
+//! min = 50
+//! template = "jsx"
+//! alt_csv = "alt.csv"
+//! loading = "lazy"
+//! decoding = "async"
+//! metadata = "copyright"
+//! icc = "preserve"
+//! crops = ["square=(max-width: 600px)", "landscape=(min-width: 1200px)"]
+//!
+//! [max_bytes_sizes]
+//! "320" = "20k"
+//!
+//! [unsharpen_sizes]
+//! "320" = "0.5,4"
+//!
+//! [focus]
+//! "team.jpg" = "0.5,0.3"
+//! ```
+//!
+//! The `alt_csv`, and a `template` that is not the name of a bundled one, are files relative to
+//! the `srcset.toml`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::opts::{Opts, parse_bytes, parse_filter, parse_unsharpen};
+use crate::template::Template;
+use crate::alt::AltMap;
+use crate::metadata::Policy;
+use crate::icc::IccPolicy;
+use crate::crop::Crop;
+
+/// The name of the configuration file looked for in each directory.
+pub const CONFIG_FILE: &str = "srcset.toml";
+
+/// The settings of one `srcset.toml`; each one left out is inherited.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub sizes: Option<Vec<u32>>,
+    /// The CSS display width of density mode, used in place of the sizes.
+    pub density: Option<u32>,
+    pub densities: Option<Vec<u32>>,
+    /// The `sizes` attribute of the tag, such as `"(min-width: 1024px) 50vw, 100vw"`.
+    pub sizes_attr: Option<String>,
+    /// The output types, the last being the fallback.
+    pub formats: Option<Vec<String>>,
+    pub quality: Option<u8>,
+    /// Encode WebP losslessly rather than at the quality.
+    pub lossless: Option<bool>,
+    /// The AVIF encoder speed, 1-10.
+    pub avif_speed: Option<u8>,
+    /// The byte budget of each variant, such as `"80k"`.
+    pub max_bytes: Option<String>,
+    /// The byte budget by width, such as `"320" = "20k"`.
+    pub max_bytes_sizes: Option<BTreeMap<String, String>>,
+    /// The least SSIM of each variant, such as `0.98`, in place of the quality.
+    pub ssim: Option<f64>,
+    /// The resampling filter: `nearest`, `triangle`, `catmullrom`, `gaussian` or `lanczos3`.
+    pub filter: Option<String>,
+    /// Sigma and threshold as `"0.25,8"`.
+    pub unsharpen: Option<String>,
+    /// Sharpen each variant; on by default.
+    pub sharpen: Option<bool>,
+    /// Scale the sigma with the downscale ratio of each variant.
+    pub sharpen_by_ratio: Option<bool>,
+    /// Sigma and threshold by width, such as `"320" = "0.5,4"`.
+    pub unsharpen_sizes: Option<BTreeMap<String, String>>,
+    pub prefix: Option<String>,
+    pub nested: Option<bool>,
+    /// Minimum size of image to process in kb.
+    pub min: Option<u64>,
+    /// A bundled template name or the path of a template file.
+    pub template: Option<String>,
+    /// A CSV of `filename,alt text` rows.
+    pub alt_csv: Option<String>,
+    /// The `loading` and `decoding` attributes of the <img>.
+    pub loading: Option<String>,
+    pub decoding: Option<String>,
+    /// The metadata kept: `strip`, `copyright` or `all`.
+    pub metadata: Option<String>,
+    /// The colour profile handling: `srgb` or `preserve`.
+    pub icc: Option<String>,
+    /// The art-directed crops, each `name=media` such as `"square=(max-width: 600px)"`.
+    pub crops: Option<Vec<String>>,
+    /// The focal point of the crops by file name, such as `"0.5,0.3"`; these add to those of
+    /// the parent directories.
+    pub focus: Option<BTreeMap<String, String>>,
+}
+
+impl Config {
+    /// Read the `srcset.toml` in the directory, if there is one.
+    pub fn load(dir: &Path) -> Result<Option<Config>> {
+        let path = dir.join(CONFIG_FILE);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| Error::Config(path.clone(), e.to_string()))?;
+
+        // Files are relative to the directory of the configuration
+        if let Some(template) = &mut config.template {
+            if Template::bundled(template).is_none() {
+                *template = dir.join(&*template).to_string_lossy().into_owned();
+            }
+        }
+        if let Some(alt_csv) = &mut config.alt_csv {
+            *alt_csv = dir.join(&*alt_csv).to_string_lossy().into_owned();
+        }
+        Ok(Some(config))
+    }
+
+    /// Set each option present onto `opts`.
+    pub fn apply(&self, opts: &mut Opts) -> Result<()> {
+        if let Some(sizes) = &self.sizes { opts.sizes = sizes.clone(); }
+        if let Some(density) = self.density { opts.density = Some(density); }
+        if let Some(densities) = &self.densities { opts.densities = densities.clone(); }
+        if let Some(sizes_attr) = &self.sizes_attr { opts.sizes_attr = Some(sizes_attr.clone()); }
+        if let Some(formats) = &self.formats {
+            opts.extensions = formats.iter().map(|f| f.trim().to_lowercase()).filter(|f| !f.is_empty()).collect();
+        }
+        if let Some(quality) = self.quality { opts.quality = quality; }
+        if let Some(lossless) = self.lossless { opts.is_lossless = lossless; }
+        if let Some(speed) = self.avif_speed { opts.avif_speed = speed; }
+        if let Some(ssim) = self.ssim { opts.ssim = Some(ssim); }
+        if let Some(max_bytes) = &self.max_bytes { opts.max_bytes = Some(parse_bytes(max_bytes)?); }
+        if let Some(sizes) = &self.max_bytes_sizes {
+            opts.max_bytes_sizes = sizes.iter().map(|(width, max_bytes)| {
+                let width = width.trim().parse::<u32>()
+                    .map_err(|_| Error::InvalidOption(format!("invalid size {:?}", width.trim())))?;
+                Ok((width, parse_bytes(max_bytes)?))
+            }).collect::<Result<_>>()?;
+        }
+        if let Some(filter) = &self.filter { opts.filter = parse_filter(filter)?; }
+        if let Some(unsharpen) = &self.unsharpen {
+            (opts.sigma, opts.thresh) = parse_unsharpen(unsharpen)?;
+        }
+        if let Some(sharpen) = self.sharpen { opts.is_sharpen = sharpen; }
+        if let Some(by_ratio) = self.sharpen_by_ratio { opts.is_sharpen_by_ratio = by_ratio; }
+        if let Some(sizes) = &self.unsharpen_sizes {
+            opts.unsharpen_sizes = sizes.iter().map(|(width, unsharpen)| {
+                let width = width.trim().parse::<u32>()
+                    .map_err(|_| Error::InvalidOption(format!("invalid size {:?}", width.trim())))?;
+                Ok((width, parse_unsharpen(unsharpen)?))
+            }).collect::<Result<_>>()?;
+        }
+        if let Some(prefix) = &self.prefix { opts.prefix = prefix.clone(); }
+        if let Some(nested) = self.nested { opts.is_nested = nested; }
+        if let Some(min) = self.min { opts.min_size = min * 1024; }
+        if let Some(template) = &self.template { opts.template = Template::from_arg(template)?; }
+        if let Some(loading) = &self.loading { opts.loading = Some(loading.clone()); }
+        if let Some(decoding) = &self.decoding { opts.decoding = Some(decoding.clone()); }
+        if let Some(metadata) = &self.metadata { opts.metadata = metadata.parse::<Policy>()?; }
+        if let Some(icc) = &self.icc { opts.icc = icc.parse::<IccPolicy>()?; }
+        if let Some(crops) = &self.crops {
+            opts.crops = crops.iter().map(|c| c.parse::<Crop>()).collect::<Result<_>>()?;
+        }
+        if let Some(focus) = &self.focus {
+            for (name, point) in focus {
+                opts.focus.insert(name.clone(), point.parse()?);
+            }
+        }
+        if let Some(alt_csv) = &self.alt_csv { opts.alts = Some(std::sync::Arc::new(AltMap::load(alt_csv)?)); }
+        Ok(())
+    }
+}
+
+/// The options for the images in `dir`, given those of its parent: any `srcset.toml` in `dir`
+/// applied and then the explicit overrides on top. Returns `None` when there is no file there.
+pub fn dir_opts(dir: &Path, parent: &Opts) -> Result<Option<Opts>> {
+    let config = match Config::load(dir)? {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let mut opts = parent.clone();
+    config.apply(&mut opts)?;
+    parent.overrides.apply(&mut opts)?;
+    opts.validate()?;
+    Ok(Some(opts))
+}
+
+/// The options for a single file: any `srcset.toml` in its own directory, which is the input root.
+pub fn file_opts(path: &Path, opts: &Opts) -> Result<Opts> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut opts = dir_opts(dir, opts)?.unwrap_or_else(|| opts.clone());
+
+    // Nesting is ignored for a single file
+    opts.is_nested = false;
+    Ok(opts)
+}
@@ -0,0 +1,233 @@
+//! Art-directed crops: each crop is an aspect ratio and the media condition of its `<source>`,
+//! such as `square=(max-width: 600px)`, saved as a set of variants of its own named
+//! `square-320w.webp` and so on.
+//!
+//! A crop keeps as much of the image as the ratio allows, centred as near as it can be on the
+//! focal point. The focal point is read from a `my_image.focus` sidecar or else the `[focus]` table
+//! of a `srcset.toml`, keyed by file name, as fractions of the width and height such as `0.5,0.3`
+//! or `50%,30%`. Failing both it is the busiest part of the image by its edges.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::DynamicImage;
+use serde::Serialize;
+use crate::error::{Error, Result};
+use crate::opts::Opts;
+
+/// The named ratios; any other is given as `WxH`, such as `16x9`.
+pub const PRESETS: [(&str, u32, u32); 4] = [("square", 1, 1), ("portrait", 4, 5), ("landscape", 16, 9), ("story", 9, 16)];
+
+/// The long side of the thumbnail the focal point is found on.
+const SALIENCY_SIZE: u32 = 128;
+
+/// One art-directed crop.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Crop {
+    /// A preset name or `WxH`; also the prefix of its file names.
+    pub name: String,
+    pub ratio: (u32, u32),
+    /// The media condition of its `<source>`.
+    pub media: String,
+}
+
+impl FromStr for Crop {
+    type Err = Error;
+
+    /// Parse `name=media`, such as `square=(max-width: 600px)` or `16x9=(min-width: 1200px)`.
+    fn from_str(s: &str) -> Result<Crop> {
+        let invalid = |why: &str| Error::InvalidOption(format!("crop {:?} {}", s, why));
+
+        let (name, media) = s.split_once('=').ok_or_else(|| invalid("is not name=media, such as square=(max-width: 600px)"))?;
+        let (name, media) = (name.trim().to_lowercase(), media.trim().to_string());
+        if media.is_empty() {
+            return Err(invalid("has no media condition"));
+        }
+
+        let preset = PRESETS.iter().find(|(n, _, _)| *n == name).map(|(_, w, h)| (*w, *h));
+        let ratio = match preset {
+            Some(ratio) => ratio,
+            None => name.split_once('x')
+                .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+                .filter(|(w, h)| *w > 0 && *h > 0)
+                .ok_or_else(|| invalid("is neither square, portrait, landscape, story nor a ratio such as 16x9"))?,
+        };
+        Ok(Crop { name, ratio, media })
+    }
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.media)
+    }
+}
+
+/// Parse crops separated by `;`, as given on the command line.
+pub fn parse_crops(s: &str) -> Result<Vec<Crop>> {
+    s.split(';').map(str::trim).filter(|c| !c.is_empty()).map(str::parse).collect()
+}
+
+impl Crop {
+    /// The width and height of the crop of an image `width` by `height`.
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let (rw, rh) = (self.ratio.0 as u64, self.ratio.1 as u64);
+        match width as u64 * rh > height as u64 * rw {
+            true => (((height as u64 * rw) / rh).max(1) as u32, height),
+            false => (width, ((width as u64 * rh) / rw).max(1) as u32),
+        }
+    }
+
+    /// Cut the crop out of the image, centred as near as it can be on the focal point.
+    pub fn apply(&self, img: &DynamicImage, focus: FocalPoint) -> DynamicImage {
+        let (width, height) = (img.width(), img.height());
+        let (cw, ch) = self.dimensions(width, height);
+        let place = |size: u32, crop: u32, at: f32| ((size as f32 * at - crop as f32 / 2.0).max(0.0) as u32).min(size - crop);
+        img.crop_imm(place(width, cw, focus.x), place(height, ch, focus.y), cw, ch)
+    }
+}
+
+/// A point of the image as fractions of its width and height, `0.5,0.5` being the centre.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for FocalPoint {
+    fn default() -> Self {
+        FocalPoint { x: 0.5, y: 0.5 }
+    }
+}
+
+impl FromStr for FocalPoint {
+    type Err = Error;
+
+    /// Parse `x,y` as fractions, `0.5,0.3`, or percentages, `50%,30%`.
+    fn from_str(s: &str) -> Result<FocalPoint> {
+        let fraction = |v: &str| {
+            let v = v.trim();
+            match v.strip_suffix('%') {
+                Some(pc) => pc.trim().parse::<f32>().ok().map(|pc| pc / 100.0),
+                None => v.parse::<f32>().ok(),
+            }.filter(|f| (0.0..=1.0).contains(f))
+        };
+        s.split_once(',')
+            .and_then(|(x, y)| Some(FocalPoint { x: fraction(x)?, y: fraction(y)? }))
+            .ok_or_else(|| Error::InvalidOption(format!("focal point {:?} is not x,y such as 0.5,0.3 or 50%,30%", s)))
+    }
+}
+
+/// The focal point given for the image in a `my_image.focus` sidecar or a `[focus]` table.
+pub fn given_focal_point(path: &Path, opts: &Opts) -> Result<Option<FocalPoint>> {
+    match std::fs::read_to_string(path.with_extension("focus")) {
+        Ok(text) => Ok(Some(text.parse()?)),
+        Err(_) => Ok(path.file_name().and_then(|n| n.to_str()).and_then(|n| opts.focus.get(n)).copied()),
+    }
+}
+
+/// The focal point given for the image, or else the centre of its busiest part.
+pub fn focal_point(path: &Path, img: &DynamicImage, opts: &Opts) -> Result<FocalPoint> {
+    Ok(given_focal_point(path, opts)?.unwrap_or_else(|| salient_point(img)))
+}
+
+/// The centre of the window a third of the size of the image holding the most edges, found on a
+/// small greyscale copy. A flat image has its centre.
+pub fn salient_point(img: &DynamicImage) -> FocalPoint {
+    let grey = img.thumbnail(SALIENCY_SIZE, SALIENCY_SIZE).to_luma8();
+    let (w, h) = (grey.width() as usize, grey.height() as usize);
+    if w < 3 || h < 3 {
+        return FocalPoint::default();
+    }
+
+    // The gradient at each pixel, summed into an integral image
+    let at = |x: usize, y: usize| grey.get_pixel(x as u32, y as u32)[0] as i32;
+    let mut sums = vec![0u64; (w + 1) * (h + 1)];
+    for y in 0..h {
+        for x in 0..w {
+            let edge = match x > 0 && y > 0 && x + 1 < w && y + 1 < h {
+                true => ((at(x + 1, y) - at(x - 1, y)).abs() + (at(x, y + 1) - at(x, y - 1)).abs()) as u64,
+                false => 0,
+            };
+            sums[(y + 1) * (w + 1) + x + 1] = edge + sums[y * (w + 1) + x + 1] + sums[(y + 1) * (w + 1) + x] - sums[y * (w + 1) + x];
+        }
+    }
+    let sum = |x: usize, y: usize, bw: usize, bh: usize| {
+        sums[(y + bh) * (w + 1) + x + bw] + sums[y * (w + 1) + x] - sums[y * (w + 1) + x + bw] - sums[(y + bh) * (w + 1) + x]
+    };
+
+    let (bw, bh) = ((w / 3).max(1), (h / 3).max(1));
+    let mut best = (0, (w - bw) / 2, (h - bh) / 2);
+    for y in 0..=h - bh {
+        for x in 0..=w - bw {
+            let energy = sum(x, y, bw, bh);
+            if energy > best.0 {
+                best = (energy, x, y);
+            }
+        }
+    }
+
+    let (_, x, y) = best;
+    FocalPoint { x: (x + bw / 2) as f32 / w as f32, y: (y + bh / 2) as f32 / h as f32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn parses_presets_and_ratios() {
+        let crop: Crop = "Square = (max-width: 600px)".parse().unwrap();
+        assert_eq!(crop, Crop { name: "square".to_string(), ratio: (1, 1), media: "(max-width: 600px)".to_string() });
+        assert_eq!("16x9=(min-width: 1200px)".parse::<Crop>().unwrap().ratio, (16, 9));
+        assert_eq!("story=(orientation: portrait)".parse::<Crop>().unwrap().ratio, (9, 16));
+
+        assert!("square".parse::<Crop>().is_err());
+        assert!("square=".parse::<Crop>().is_err());
+        assert!("wide=(min-width: 1200px)".parse::<Crop>().is_err());
+        assert!("16x0=(min-width: 1200px)".parse::<Crop>().is_err());
+    }
+
+    #[test]
+    fn parses_crops_separated_by_semicolons() {
+        let crops = parse_crops("square=(max-width: 600px); landscape=(min-width: 1200px);").unwrap();
+        assert_eq!(crops.iter().map(Crop::to_string).collect::<Vec<_>>(),
+            ["square=(max-width: 600px)", "landscape=(min-width: 1200px)"]);
+        assert!(parse_crops("").unwrap().is_empty());
+        assert!(parse_crops("square=(max-width: 600px); oval=(hover)").is_err());
+    }
+
+    #[test]
+    fn parses_focal_points_as_fractions_or_percentages() {
+        assert_eq!("0.5,0.3".parse::<FocalPoint>().unwrap(), FocalPoint { x: 0.5, y: 0.3 });
+        assert_eq!(" 25% , 100% ".parse::<FocalPoint>().unwrap(), FocalPoint { x: 0.25, y: 1.0 });
+        assert!("0.5".parse::<FocalPoint>().is_err());
+        assert!("1.5,0.5".parse::<FocalPoint>().is_err());
+        assert!("50%,-10%".parse::<FocalPoint>().is_err());
+    }
+
+    #[test]
+    fn keeps_as_much_of_the_image_as_the_ratio_allows() {
+        let square: Crop = "square=(hover)".parse().unwrap();
+        assert_eq!(square.dimensions(400, 300), (300, 300));
+        assert_eq!(square.dimensions(300, 400), (300, 300));
+        let wide: Crop = "1000x1=(hover)".parse().unwrap();
+        assert_eq!(wide.dimensions(400, 300), (400, 1));
+        let tall: Crop = "1x1000=(hover)".parse().unwrap();
+        assert_eq!(tall.dimensions(400, 300), (1, 300));
+    }
+
+    #[test]
+    fn stops_the_crop_at_the_edges() {
+        // Each column is as bright as its x, so the left edge of the crop shows where it was cut
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(400, 300, |x, _| Luma([(x / 2) as u8])));
+        let square: Crop = "square=(hover)".parse().unwrap();
+        let left_edge = |focus| square.apply(&img, focus).to_luma8().get_pixel(0, 0)[0];
+
+        assert_eq!(left_edge(FocalPoint { x: 0.0, y: 0.0 }), 0);
+        assert_eq!(left_edge(FocalPoint::default()), 25);
+        assert_eq!(left_edge(FocalPoint { x: 1.0, y: 1.0 }), 50);
+        assert_eq!(square.apply(&img, FocalPoint { x: 1.0, y: 1.0 }).width(), 300);
+    }
+}
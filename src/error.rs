@@ -0,0 +1,42 @@
+//! The errors returned across the library boundary.
+
+use std::path::PathBuf;
+
+/// Everything that can go wrong while resizing and saving images.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Reading the source or writing an output failed.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The image could not be decoded or encoded.
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+
+    /// The manifest could not be serialized.
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A `srcset.toml` could not be parsed.
+    #[error("config error in {0:?}: {1}")]
+    Config(PathBuf, String),
+
+    /// An option could not be parsed or is out of range.
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
+
+    /// A markup template could not be read, compiled or rendered.
+    #[error("template error: {0}")]
+    Template(String),
+
+    /// The image is narrower than every requested size.
+    #[error("image width {0} is narrower than every size")]
+    TooNarrow(u32),
+
+    /// A path is not valid UTF-8, has no file name, or lies outside the input path.
+    #[error("invalid path: {0:?}")]
+    InvalidPath(PathBuf),
+}
+
+/// A `Result` using the library [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
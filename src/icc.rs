@@ -0,0 +1,192 @@
+//! The ICC colour profile of a source image, by policy:
+//!
+//! - `srgb` converts the pixels of an RGB image to sRGB, the default. Browsers take an untagged
+//!   image to be sRGB, so one in Adobe RGB or Display P3 looks washed out once its profile is gone;
+//! - `preserve` leaves the pixels as they are and embeds the profile in each output instead. Only
+//!   JPEG, PNG and WebP carry a profile, so sources written only to other types are converted
+//!   all the same.
+//!
+//! A grey profile is kept rather than converted, and one that does not fit the decoded pixels,
+//! such as the CMYK profile of a CMYK JPEG, is dropped.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use image::{DynamicImage, ImageFormat};
+use img_parts::{DynImage, ImageICC};
+use qcms::{DataType, Intent, Profile, Transform};
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// What is done with the colour profile of the source.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IccPolicy {
+    #[default]
+    Srgb,
+    Preserve,
+}
+
+impl FromStr for IccPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IccPolicy> {
+        match s.trim().to_lowercase().as_str() {
+            "srgb" | "convert" => Ok(IccPolicy::Srgb),
+            "preserve" | "keep" => Ok(IccPolicy::Preserve),
+            _ => Err(Error::InvalidOption(format!("icc {:?} is not srgb or preserve", s))),
+        }
+    }
+}
+
+/// The TIFF tag InterColorProfile, which holds the ICC profile of a TIFF.
+const INTER_COLOR_PROFILE: exif::Tag = exif::Tag(exif::Context::Tiff, 34675);
+
+/// The ICC profile embedded in a JPEG, PNG, WebP or TIFF, if any.
+pub fn icc_profile(path: &Path) -> Option<Vec<u8>> {
+    let bytes = std::fs::read(path).ok()?;
+    match bytes.get(..4) {
+        Some(b"II*\0" | b"MM\0*") => tiff_profile(bytes),
+        _ => match DynImage::from_bytes(bytes.into()) {
+            Ok(Some(img)) => img.icc_profile().map(|b| b.to_vec()),
+            _ => None,
+        },
+    }
+}
+
+/// The profile in the first IFD of a TIFF, which img-parts does not read.
+fn tiff_profile(bytes: Vec<u8>) -> Option<Vec<u8>> {
+    let tiff = exif::Reader::new().read_raw(bytes).ok()?;
+    match &tiff.get_field(INTER_COLOR_PROFILE, exif::In::PRIMARY)?.value {
+        exif::Value::Undefined(icc, _) | exif::Value::Byte(icc) => Some(icc.clone()),
+        _ => None,
+    }
+}
+
+/// Whether variants of the type, by extension, can embed a profile; the others are untagged.
+pub fn carries_profile(ext: &str) -> bool {
+    matches!(ImageFormat::from_extension(ext), Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP))
+}
+
+/// Apply the policy to the decoded image, returning the image and the profile to embed in its
+/// outputs, if any.
+pub fn apply_profile(img: DynamicImage, icc: Option<Vec<u8>>, policy: IccPolicy) -> (DynamicImage, Option<Vec<u8>>) {
+    let Some(icc) = icc else { return (img, None) };
+
+    let is_grey = matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_));
+    // The colour space signature in the profile header
+    match (icc.get(16..20), is_grey) {
+        (Some(b"RGB "), false) => (),
+        (Some(b"GRAY"), true) => return (img, Some(icc)),
+        _ => return (img, None),
+    }
+
+    if policy == IccPolicy::Preserve {
+        return (img, Some(icc));
+    }
+    let Some(profile) = Profile::new_from_slice(&icc, false) else { return (img, Some(icc)) };
+    if profile.is_sRGB() {
+        return (img, None);
+    }
+
+    let srgb = Profile::new_sRGB();
+    match img.color().has_alpha() {
+        true => match Transform::new(&profile, &srgb, DataType::RGBA8, Intent::Perceptual) {
+            Some(transform) => {
+                let mut buf = img.into_rgba8();
+                transform.apply(&mut buf);
+                (DynamicImage::ImageRgba8(buf), None)
+            },
+            None => (img, Some(icc)),
+        },
+        false => match Transform::new(&profile, &srgb, DataType::RGB8, Intent::Perceptual) {
+            Some(transform) => {
+                let mut buf = img.into_rgb8();
+                transform.apply(&mut buf);
+                (DynamicImage::ImageRgb8(buf), None)
+            },
+            None => (img, Some(icc)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, RgbImage};
+
+    /// The primaries of Display P3 with a gamma of 2.2.
+    const DISPLAY_P3: &[u8] = include_bytes!("../testdata/display-p3.icc");
+
+    fn saturated() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| image::Rgb([255, (x * 16) as u8, (y * 8) as u8])))
+    }
+
+    #[test]
+    fn converts_to_srgb_by_default() {
+        let img = saturated();
+        let (converted, icc) = apply_profile(img.clone(), Some(DISPLAY_P3.to_vec()), IccPolicy::Srgb);
+        assert_eq!(icc, None);
+        assert_eq!(converted.color(), img.color());
+        assert_ne!(converted.as_bytes(), img.as_bytes());
+    }
+
+    #[test]
+    fn keeps_the_pixels_and_profile_when_preserved() {
+        let img = saturated();
+        let (kept, icc) = apply_profile(img.clone(), Some(DISPLAY_P3.to_vec()), IccPolicy::Preserve);
+        assert_eq!(icc.as_deref(), Some(DISPLAY_P3));
+        assert_eq!(kept.as_bytes(), img.as_bytes());
+    }
+
+    #[test]
+    fn drops_a_profile_that_does_not_fit() {
+        let grey = DynamicImage::ImageLuma8(GrayImage::new(4, 4));
+        for policy in [IccPolicy::Srgb, IccPolicy::Preserve] {
+            // An RGB profile on grey pixels, a grey one on RGB pixels, and ones that are no profile
+            assert_eq!(apply_profile(grey.clone(), Some(DISPLAY_P3.to_vec()), policy).1, None);
+            let mut grey_profile = DISPLAY_P3.to_vec();
+            grey_profile[16..20].copy_from_slice(b"GRAY");
+            assert_eq!(apply_profile(saturated(), Some(grey_profile), policy).1, None);
+            assert_eq!(apply_profile(saturated(), Some(b"RGB".to_vec()), policy).1, None);
+            assert_eq!(apply_profile(saturated(), Some(vec![]), policy).1, None);
+        }
+        // A header that claims RGB over a body qcms cannot read is kept rather than converted
+        let mut broken = DISPLAY_P3[..128].to_vec();
+        broken.extend([0xff; 8]);
+        let (img, icc) = apply_profile(saturated(), Some(broken.clone()), IccPolicy::Srgb);
+        assert_eq!(img.as_bytes(), saturated().as_bytes());
+        assert_eq!(icc, Some(broken));
+    }
+
+    #[test]
+    fn reads_the_profile_of_a_tiff() {
+        let field = exif::Field { tag: INTER_COLOR_PROFILE, ifd_num: exif::In::PRIMARY, value: exif::Value::Undefined(DISPLAY_P3.to_vec(), 0) };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&field);
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, true).unwrap();
+
+        let path = std::env::temp_dir().join(format!("srcset-icc-{}.tif", std::process::id()));
+        std::fs::write(&path, tiff.into_inner()).unwrap();
+        let icc = icc_profile(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(icc.as_deref(), Some(DISPLAY_P3));
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[test]
+    fn reads_the_profile_of_a_jpeg() {
+        use crate::img_ext::ImgExt;
+        use crate::metadata::Metadata;
+
+        let metadata = Metadata { icc: Some(DISPLAY_P3.to_vec()), ..Metadata::default() };
+        let path = std::env::temp_dir().join(format!("srcset-icc-{}.jpg", std::process::id()));
+        std::fs::write(&path, saturated().encode_with_metadata(ImageFormat::Jpeg, 90, &metadata).unwrap()).unwrap();
+        let icc = icc_profile(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(icc.as_deref(), Some(DISPLAY_P3));
+        assert!(carries_profile("jpg") && !carries_profile("avif") && !carries_profile("gif"));
+    }
+}
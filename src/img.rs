@@ -1,276 +1,675 @@
 //! Takes a filepath, opens an image and the saves the image in the format specified
 //! by either the original file extension or that provided in options.
 
-use std::path::Path;
-use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use rayon::prelude::*;
-use anyhow::Result;
-use image::DynamicImage;
-use image::GenericImageView;
-use yansi::Paint;
+use image::{DynamicImage, ImageFormat};
+use image::imageops::FilterType;
+use serde::Serialize;
 
+use crate::cache::Cache;
+use crate::error::{Error, Result};
 use crate::opts::{Opts, Metrics};
-use crate::utils::{use_fileext,mk_dir, path_from_array};
+use crate::utils::{use_fileexts, mk_dir, file_stem, image_dir, path_str, is_fresh};
 use crate::img_ext::ImgExt;
+use crate::template::{TagData, TagSource, TagVariant};
+use crate::alt::alt_text;
+use crate::orientation::{orientation, upright_dimensions, make_upright};
+use crate::metadata::Metadata;
+use crate::icc::{icc_profile, apply_profile, carries_profile, IccPolicy};
+use crate::crop::{Crop, FocalPoint, focal_point};
+
+/// One resized image written (or, on a test run, planned) by `scale_and_save`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Variant {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    /// The size of the written file; zero on a test run.
+    pub bytes: u64,
+    #[serde(serialize_with = "crate::manifest::serialize_format")]
+    pub format: ImageFormat,
+    /// The pixel density in density mode, such as 2 for `2x`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub density: Option<u32>,
+    /// The name of the art-directed crop it was cut from, such as `square`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<String>,
+    /// The encoder quality of a JPEG, WebP or AVIF, as chosen for any SSIM wanted and lowered to
+    /// fit any byte budget; unknown on a test run and for an output of a previous run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+}
 
-/// Process the image provided in the path.
-/// Iterate through the sizes and create a scaled image for each
-pub fn process_image(path: &Path, opts: &Opts, m: &mut Metrics) -> Result<()>
-{
-    // Use the open function to load an image from a Path.
-    // `open` returns a `DynamicImage` on success.
-    let img:DynamicImage = image::open(path)?;
+/// Everything `process_image` produced from one source image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProcessedImage {
+    pub source: PathBuf,
+    /// Width and height of the source image, once turned upright by any EXIF orientation.
+    pub width: u32,
+    pub height: u32,
+    /// The largest size, used for `src` by legacy browsers.
+    pub legacy: Variant,
+    /// One per size and format, smallest size first, then those of each crop.
+    pub variants: Vec<Variant>,
+    /// The markup rendered by the template, by default an `<img>` or `<picture>`.
+    pub tag: String,
+    /// The `srcset.txt` holding the tag, if one was written.
+    pub tagfile: Option<PathBuf>,
+    /// Every output was already newer than the source, so nothing was written.
+    pub up_to_date: bool,
+}
 
-    if opts.is_file {
-        println!("{:?}", Paint::green(&path));
-    } else {
-        println!("{:?}", Paint::green(path.strip_prefix(opts.inpath.as_path()).unwrap()));
+impl Variant {
+    /// The file name alone, such as `320w.webp`.
+    pub fn file_name(&self) -> &str {
+        self.path.file_name().and_then(|n| n.to_str()).unwrap_or_default()
     }
 
-    let (w,h) = img.dimensions();
+    /// The descriptor within a srcset, such as `320w` or in density mode `2x`.
+    pub fn descriptor(&self) -> String {
+        match self.density {
+            Some(d) => format!("{}x", d),
+            None => format!("{}w", self.width),
+        }
+    }
+}
+
+impl ProcessedImage {
+    /// The widths of the variants, smallest first.
+    pub fn sizes(&self) -> Vec<u32> {
+        let mut sizes: Vec<u32> = self.variants.iter().map(|v| v.width).collect();
+        sizes.dedup();
+        sizes
+    }
+}
+
+/// Process the image provided in the path.
+/// Iterate through the sizes and create a scaled image for each, in every requested format
+pub fn process_image(path: &Path, opts: &Opts, m: &mut Metrics) -> Result<ProcessedImage>
+{
+    // Read the dimensions from the header; decode only once the image is known to be needed.
+    // A photo taken on its side is stored sideways with an EXIF orientation to turn it upright
+    let orientation = orientation(path);
+    let (w,h) = image::image_dimensions(path)?;
+    let (w,h) = upright_dimensions(w, h, orientation);
     let aspect =  w as f32 / h as f32;
 
+    // The widths wanted; in density mode the display width at each density
+    let widths: Vec<u32> = match opts.density {
+        Some(css) => opts.densities.iter().map(|d| css * d).collect(),
+        None => opts.sizes.clone(),
+    };
+
     // Pick maximum array slice based on width of image
     let maxsize = match opts.use_largest {
-        true => *opts.sizes.last().unwrap(),
+        true => *widths.iter().max().unwrap_or(&w),
         false => w,
     };
 
     // Pick maximum array slice based on width of image
-    let sizes = match strip_sizes(maxsize, &opts.sizes) {
-        None => return Ok(()),
-        Some(v) => v, 
-    };
+    let sizes = strip_sizes(maxsize, &widths).ok_or(Error::TooNarrow(w))?;
 
-    println!("\n{:?}\n\n", Paint::blue(&sizes) );
+    // The largest size is the legacy one
+    let max = *sizes.last().unwrap();
 
+    // The art-directed crops with their sizes and aspect ratios; a crop too narrow for any size is
+    // left out, as is an image
+    let crops: Vec<(&Crop, Vec<u32>, f32)> = opts.crops.iter().filter_map(|crop| {
+        let (cw, _) = crop.dimensions(w, h);
+        let max = if opts.use_largest { maxsize } else { cw };
+        strip_sizes(max, &widths).map(|sizes| (crop, sizes, crop.ratio.0 as f32 / crop.ratio.1 as f32))
+    }).collect();
 
-    // The largest size is the legacy one
-    let max = sizes.last().unwrap();
-    
-    if opts.is_verbose { print_image_details(&img, &path)};
+    // The last type is the fallback used by the <img> itself
+    let exts = use_fileexts(path, &opts.extensions)?;
+    let fallback = *exts.last().unwrap();
+    let file_name = file_stem(path)?;
+
+    let dir = image_dir(path_str(&opts.outpath)?, path, opts)?;
 
-    let ext = use_fileext(&path, &opts.extension);
-    let file_name = path.file_stem().and_then(OsStr::to_str).unwrap();
-    
     // Legacy should use the largest size of the provided range not the initial size. Could be too large
-    let np = match opts.is_nested {
-        true => path_from_array(&[
-                        &opts.outpath.to_str().unwrap(),
-                        &path.strip_prefix(opts.inpath.as_path()).unwrap().parent().unwrap().to_str().unwrap(),
-                        &file_name,
-                        &("legacy.".to_owned()+ext)]),
-        _ =>    path_from_array(&[
-                    &opts.outpath.to_str().unwrap(),
-                    &file_name,
-                    &("legacy.".to_owned()+ext)]
-                    ),
+    let np = dir.join("legacy.".to_owned() + fallback);
+
+    // THE SRCSET TAG directory
+    let sp = image_dir(&opts.prefix, path, opts)?;
+    let sp = path_str(&sp)?;
+
+    // THE SRCSET.TXT DESINATION
+    let tagfile = match opts.is_tagfile {
+        true => Some(dir.join("srcset.txt")),
+        false => None,
     };
 
-    // For the legacy image, do not just copy the original resize to max size of 1440 or less
-    if !opts.is_test {
-        let legacy_img = img.resize_to_fill(*max, (*max as f32/aspect) as u32, image::imageops::FilterType::Lanczos3);
+    // Without alt text the image is marked as decorative, which may well not be right
+    let alt = alt_text(path, opts).unwrap_or_else(|| {
+        if !opts.is_quiet { eprintln!("WARNING: No alt text for {:?}, using alt=\"\"", path) }
+        String::new()
+    });
+
+    // Skip the work when every output is newer than the source, or was produced before from
+    // the same content and options
+    let cache_key = match &opts.cache {
+        Some(_) if !opts.is_test => Some(Cache::key(path, opts, &sizes, &exts, &dir)?),
+        _ => None,
+    };
 
-        legacy_img.unsharpen(opts.sigma, opts.thresh);
+    if (opts.is_incremental || cache_key.is_some()) && !opts.is_test {
+        // With hashed names an output without a hash, left by an earlier plain run, is none of
+        // them; any one missing has the image made again
+        let existing = |img_path: PathBuf, nwidth: u32, nheight: u32, crop: Option<&str>| match existing_path(img_path, opts) {
+            Some(img_path) => existing_variant(img_path, nwidth, nheight, crop, opts).map(Some),
+            None => Ok(None),
+        };
+        let legacy = existing(np.clone(), max, height_for(max, aspect), None)?;
+        let mut variants = sizes.iter()
+            .flat_map(|sz| exts.iter().map(|ext| existing(variant_path(&dir, None, *sz, ext, opts), *sz, height_for(*sz, aspect), None)))
+            .collect::<Result<Vec<_>>>()?;
+        for (crop, sizes, aspect) in &crops {
+            for sz in sizes {
+                for ext in &exts {
+                    let img_path = variant_path(&dir, Some(&crop.name), *sz, ext, opts);
+                    variants.push(existing(img_path, *sz, height_for(*sz, *aspect), Some(&crop.name))?);
+                }
+            }
+        }
 
-        mk_dir(&np);
-        //legacy_img.save(&np)?;
-        //legacy_img.save_with_quality(&np, opts.quality)?;
-        legacy_img.save_safe_with_quality(&np, opts.quality)?;
+        if let (Some(legacy), Some(variants)) = (legacy, variants.into_iter().collect::<Option<Vec<_>>>()) {
+            let outputs: Vec<&Path> = std::iter::once(legacy.path.as_path())
+                .chain(variants.iter().map(|v| v.path.as_path()))
+                .chain(tagfile.as_deref())
+                .collect();
 
-        if opts.is_verbose {print_image_details(&legacy_img, &np)}
+            let cached = match (&opts.cache, &cache_key) {
+                (Some(cache), Some(key)) => cache.is_done(key, &outputs),
+                _ => false,
+            };
 
+            if cached || (opts.is_incremental && is_fresh(path, &outputs)) {
+                m.up_to_date += 1;
+                let tag = create_tag(sp, &legacy, &variants, file_name, &alt, &tag_sizes(max, opts), opts)?;
+
+                // The alt text or template may have changed without the source
+                if let Some(f) = &tagfile {
+                    if std::fs::read_to_string(f).ok().as_deref() != Some(tag.as_str()) {
+                        std::fs::write(f, &tag)?;
+                    }
+                }
+                return Ok(ProcessedImage { source: path.to_path_buf(), width: w, height: h, legacy, variants, tag, tagfile, up_to_date: true });
+            }
+        }
     }
-    m.resized = m.resized + 1;  // One resize for legacy    
-    
+
+    // Use the open function to load an image from a Path.
+    // `open` returns a `DynamicImage` on success.
+    let img:DynamicImage = make_upright(image::open(path)?, orientation);
+
+    // Convert to sRGB, or keep the profile to embed in each output; untagged pixels are taken
+    // to be sRGB, so there is no keeping it when no output can carry it
+    let carried = exts.iter().filter(|ext| carries_profile(ext)).count();
+    let policy = match carried {
+        0 => IccPolicy::Srgb,
+        _ => opts.icc,
+    };
+    let (img, icc) = apply_profile(img, icc_profile(path), policy);
+    if policy == IccPolicy::Preserve && icc.is_some() && carried < exts.len() && !opts.is_quiet {
+        let untagged: Vec<&str> = exts.iter().copied().filter(|ext| !carries_profile(ext)).collect();
+        eprintln!("WARNING: The {} variants of {:?} cannot carry its colour profile and are written without it", untagged.join(","), path);
+    }
+
+    // Read once for every output; nothing at all with the default of stripping it
+    let metadata = Metadata { icc, ..Metadata::read(path, opts.metadata) };
+
+    // For the legacy image, do not just copy the original resize to max size of 1440 or less
+    if !opts.is_test { mk_dir(&np); }
+    let legacy = resize_and_save(&img, vec![np], max, height_for(max, aspect), None, &metadata, opts)?.remove(0);
+    m.resized += 1;  // One resize for legacy
+
 
     // 320,480,640,768,960,1024,1280,1440 pixels wide
     // Iterate through the sizes and create a scaled image for each
-    match opts.is_jobs {
-    
+    let variants: Vec<Vec<Variant>> = match opts.is_jobs {
+
         // The following uses rayon parallel processes
-        true => {
-                let _r: Result<Vec<_>, _> = sizes.par_iter().map( |sz|
-                        scale_and_save(&path, &opts.outpath, &img, *sz, (*sz as f32 / aspect) as u32, &opts.extension, &opts))
-                        .collect();
-                },
-
-        false =>
-            for n in &sizes
-            {
-                scale_and_save(&path, &opts.outpath, &img, *n, (*n as f32 / aspect) as u32, &opts.extension, &opts)?;
-            }
-            ,
-     };
-    
-
-    // THE SRCSET TAG
-    let sp = match opts.is_nested {
-        true =>
-                path_from_array(&[
-                    &opts.prefix,
-                    &path.strip_prefix(opts.inpath.as_path()).unwrap().parent().unwrap().to_str().unwrap(),
-                    &file_name]
-                    ),
-        _ =>    path_from_array(&[&opts.prefix,&file_name]),
-    };
+        true => sizes.par_iter().map( |sz|
+                        scale_and_save(path, &opts.outpath, &img, *sz, height_for(*sz, aspect), &exts, &metadata, opts))
+                        .collect::<Result<Vec<_>>>()?,
 
-    let tag = create_tag(*max, sp.to_str().unwrap(), ext, &file_name, &opts.sizes);
+        false => sizes.iter().map( |n|
+                        scale_and_save(path, &opts.outpath, &img, *n, height_for(*n, aspect), &exts, &metadata, opts))
+                        .collect::<Result<Vec<_>>>()?,
+     };
+    let mut variants: Vec<Variant> = variants.into_iter().flatten().collect();
 
-    // THE SRCSET.TXT DESINATION
-    if opts.is_tagfile == true {
-        let f = match opts.is_nested {
-            true =>
-                    path_from_array(&[
-                            &opts.outpath.to_str().unwrap(),
-                            &path.strip_prefix(opts.inpath.as_path()).unwrap().parent().unwrap().to_str().unwrap(),
-                            &file_name,
-                            "srcset.txt"]
-                            ),
-
-            _ =>    path_from_array(&[
-                        &opts.outpath.to_str().unwrap(),
-                        &file_name,
-                        "srcset.txt"]),
+    // Each crop is cut around the one focal point, then resized to its own set of sizes
+    let focus = match crops.is_empty() {
+        true => FocalPoint::default(),
+        false => focal_point(path, &img, opts)?,
+    };
+    for (crop, sizes, aspect) in &crops {
+        let cropped = crop.apply(&img, focus);
+        let save = |sz: &u32| {
+            let img_paths = exts.iter().map(|ext| variant_path(&dir, Some(&crop.name), *sz, ext, opts)).collect();
+            resize_and_save(&cropped, img_paths, *sz, height_for(*sz, *aspect), Some(&crop.name), &metadata, opts)
+        };
+        let saved: Vec<Vec<Variant>> = match opts.is_jobs {
+            true => sizes.par_iter().map(save).collect::<Result<Vec<_>>>()?,
+            false => sizes.iter().map(save).collect::<Result<Vec<_>>>()?,
         };
-    
-        if opts.is_verbose { println!("{:?}", f);}
+        variants.extend(saved.into_iter().flatten());
+    }
 
-        println!("\n{}\n\n", Paint::blue(&tag) );
+    let tag = create_tag(sp, &legacy, &variants, file_name, &alt, &tag_sizes(max, opts), opts)?;
 
+    if let Some(f) = &tagfile {
         if !opts.is_test {
             std::fs::write(f, &tag)?;
         }
-    }    
+    }
+
+    if let (Some(cache), Some(key)) = (&opts.cache, cache_key) {
+        let outputs: Vec<&Path> = std::iter::once(legacy.path.as_path())
+            .chain(variants.iter().map(|v| v.path.as_path()))
+            .chain(tagfile.as_deref())
+            .collect();
+        cache.insert(key, &outputs);
+    }
+
     // Increment the counter
-    m.count = m.count + 1;
-    m.resized = m.resized+(sizes.len() as u32);
+    m.count += 1;
+    m.resized += variants.len() as u32;
 
-    Ok(())
+    Ok(ProcessedImage { source: path.to_path_buf(), width: w, height: h, legacy, variants, tag, tagfile, up_to_date: false })
 }
 
 
 
-///  Resize the image provided by path and save the resulting new image onto outpath
+///  Resize the image provided by path and save the resulting new image onto outpath,
+///  once for each of the extensions
+#[allow(clippy::too_many_arguments)]
 pub fn scale_and_save(path: &Path, outpath: &Path,
         img: &DynamicImage, nwidth: u32, nheight: u32,
-        ext: &str, opts: &Opts ) -> Result<()>
+        exts: &[&str], metadata: &Metadata, opts: &Opts ) -> Result<Vec<Variant>>
+{
+    let dir = image_dir(path_str(outpath)?, path, opts)?;
+
+    // The new paths from names, sizes and file ext
+    let img_paths = exts.iter().map(|ext| variant_path(&dir, None, nwidth, ext, opts)).collect();
+
+    resize_and_save(img, img_paths, nwidth, nheight, None, metadata, opts)
+}
+
+/// The path of one size in one format, such as `320w.webp` or in density mode `2x.webp`; that of
+/// a crop is prefixed by its name, such as `square-320w.webp`.
+fn variant_path(dir: &Path, crop: Option<&str>, nwidth: u32, ext: &str, opts: &Opts) -> PathBuf
 {
-    // Filename only with no extension
-    let file_name = path.file_stem().and_then(OsStr::to_str).unwrap();
-
-    // The filename extension. jpg, png etc A valid image extension
-    let ext = use_fileext(&path, &ext);
-
-    // The new path from names, sizes and file ext
-    let img_path = match opts.is_nested {
-        true =>
-                path_from_array(&[
-                        &outpath.to_str().unwrap(),
-                        &path.strip_prefix(opts.inpath.as_path()).unwrap().parent().unwrap().to_str().unwrap(),
-                        &file_name,
-                        &(nwidth.to_string().to_owned() + "w." + ext)]),
-        _ =>    path_from_array(&[
-                        &outpath.to_str().unwrap(),
-                        &file_name,
-                        &(nwidth.to_string().to_owned() + "w." + ext)]),
+    let prefix = crop.map(|c| c.to_string() + "-").unwrap_or_default();
+    match density(nwidth, opts) {
+        Some(d) => dir.join(prefix + &d.to_string() + "x." + ext),
+        None => dir.join(prefix + &nwidth.to_string() + "w." + ext),
+    }
+}
+
+/// The height of a variant `nwidth` wide at the aspect ratio, at least one pixel however wide the ratio.
+fn height_for(nwidth: u32, aspect: f32) -> u32
+{
+    ((nwidth as f32 / aspect) as u32).max(1)
+}
+
+/// The pixel density of a width in density mode.
+fn density(nwidth: u32, opts: &Opts) -> Option<u32>
+{
+    opts.density.map(|css| nwidth / css)
+}
+
+/// Where a previous run left an output; with hashed names, the newest file of the same
+/// size and type whatever its hash, and None when there is no hashed one.
+fn existing_path(img_path: PathBuf, opts: &Opts) -> Option<PathBuf>
+{
+    if !opts.is_hashed {
+        return Some(img_path);
+    }
+
+    let (dir, stem, ext) = (img_path.parent()?, file_stem(&img_path).ok()?, img_path.extension().and_then(|e| e.to_str())?);
+
+    std::fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some(ext))
+        .filter(|p| file_stem(p).ok()
+            .and_then(|s| s.strip_prefix(stem))
+            .and_then(|s| s.strip_prefix('.'))
+            .is_some_and(|hash| hash.len() == HASH_LEN && hash.bytes().all(|b| b.is_ascii_hexdigit())))
+        .max_by_key(|p| p.metadata().and_then(|md| md.modified()).ok())
+}
+
+/// Describe an output from a previous run, as found on disk.
+fn existing_variant(img_path: PathBuf, nwidth: u32, nheight: u32, crop: Option<&str>, opts: &Opts) -> Result<Variant>
+{
+    let format = ImageFormat::from_path(&img_path)?;
+    let bytes = img_path.metadata().map(|md| md.len()).unwrap_or(0);
+    Ok(Variant { path: img_path, width: nwidth, height: nheight, bytes, format, density: density(nwidth, opts), crop: crop.map(str::to_string), quality: None })
+}
+
+/// Resize to exactly `nwidth` by `nheight` and sharpen once, then save onto each of
+/// `img_paths` with the metadata unless on a test run.
+fn resize_and_save(img: &DynamicImage, img_paths: Vec<PathBuf>, nwidth: u32, nheight: u32, crop: Option<&str>, metadata: &Metadata, opts: &Opts) -> Result<Vec<Variant>>
+{
+    let scaled = match opts.is_test {
+        false => {
+            let scaled = resize(img, nwidth, nheight, opts.filter);
+
+            match unsharpen_for(img.width(), nwidth, opts) {
+                Some((sigma, thresh)) => Some(scaled.unsharpen(sigma, thresh)),
+                None => Some(scaled),
+            }
+        },
+        true => None,
     };
 
+    img_paths.into_iter().map(|img_path| {
+        let format = ImageFormat::from_path(&img_path)?;
 
-    if !opts.is_test {
-        let scaled = img.resize_to_fill(nwidth as u32, nheight as u32, image::imageops::FilterType::Lanczos3);
+        let (img_path, bytes, quality) = match &scaled {
+            Some(scaled) => {
+                let (img_path, quality) = save_variant(scaled, img_path, format, max_bytes_for(nwidth, opts), metadata, opts)?;
+                let bytes = img_path.metadata()?.len();
+                (img_path, bytes, opts.encoding().is_lossy(format).then_some(quality))
+            },
+            None => (img_path, 0, None),
+        };
 
+        Ok(Variant { path: img_path, width: nwidth, height: nheight, bytes, format, density: density(nwidth, opts), crop: crop.map(str::to_string), quality })
+    }).collect()
+}
 
-        scaled.unsharpen(opts.sigma, opts.thresh);
+/// A source this many times the size wanted or more is first shrunk by a box filter.
+const PRESHRINK: u32 = 4;
 
-        
-        //scaled.save(&img_path)?;
-        //scaled.save_with_quality(&img_path, opts.quality)?;
-        scaled.save_safe_with_quality(&img_path, opts.quality)?;
+/// Resize to fill exactly `nwidth` by `nheight`. A very large source is first shrunk cheaply to
+/// twice the size wanted, then resized with the filter; the filter sees as many pixels per output
+/// pixel either way, but far fewer in all. Nearest is left alone so pixel art stays sharp.
+fn resize(img: &DynamicImage, nwidth: u32, nheight: u32, filter: FilterType) -> DynamicImage
+{
+    let scale = f64::max(nwidth as f64 / img.width() as f64, nheight as f64 / img.height() as f64);
+    if filter == FilterType::Nearest || scale * PRESHRINK as f64 > 1.0 {
+        return img.resize_to_fill(nwidth, nheight, filter);
+    }
+
+    let (w, h) = ((img.width() as f64 * scale * 2.0).ceil() as u32, (img.height() as f64 * scale * 2.0).ceil() as u32);
+    img.thumbnail_exact(w, h).resize_to_fill(nwidth, nheight, filter)
+}
+
+/// The unsharpen sigma and threshold of a variant `nwidth` wide resized from `width`: those given
+/// for the size, or else the default, scaled up by half again for each halving with
+/// `is_sharpen_by_ratio`, so 2.25 times at a quarter of the width. None when sharpening is off.
+fn unsharpen_for(width: u32, nwidth: u32, opts: &Opts) -> Option<(f32, i32)>
+{
+    if !opts.is_sharpen {
+        return None;
+    }
+    let (sigma, thresh) = match opts.unsharpen_sizes.get(&nwidth) {
+        Some(unsharpen) => *unsharpen,
+        None if opts.is_sharpen_by_ratio => {
+            let ratio = (width as f32 / nwidth as f32).max(1.0);
+            (opts.sigma * 1.5f32.powf(ratio.log2()), opts.thresh)
+        },
+        None => (opts.sigma, opts.thresh),
+    };
+    // A zero sigma would blur nothing, so there is nothing to sharpen
+    (sigma > 0.0).then_some((sigma, thresh))
+}
+
+/// The byte budget of a variant `nwidth` wide: that given for the size, or else the default.
+fn max_bytes_for(nwidth: u32, opts: &Opts) -> Option<u64>
+{
+    opts.max_bytes_sizes.get(&nwidth).copied().or(opts.max_bytes)
+}
+
+/// The number of hex digits of the content hash embedded in a hashed name.
+const HASH_LEN: usize = 6;
+
+/// Save onto `img_path`, or with hashed names onto `img_path` with a short hash of the encoded
+/// bytes before the extension, such as `320w.3fa9c1.webp`. The quality is the lowest to reach any
+/// SSIM wanted, then lowered further as need be to fit any byte budget. Returns the path written
+/// and the quality it was encoded at.
+fn save_variant(scaled: &DynamicImage, img_path: PathBuf, format: ImageFormat, max_bytes: Option<u64>, metadata: &Metadata, opts: &Opts) -> Result<(PathBuf, u8)>
+{
+    let encoding = match opts.ssim {
+        Some(threshold) => opts.encoding().with_quality(scaled.quality_for_ssim(format, opts.encoding(), threshold)?),
+        None => opts.encoding(),
+    };
+
+    if !opts.is_hashed && metadata.is_empty() && max_bytes.is_none() {
+        scaled.save_safe_with_quality(&img_path, encoding)?;
+        return Ok((img_path, encoding.quality));
+    }
 
+    let (buf, quality) = match max_bytes {
+        Some(max_bytes) => scaled.encode_within(format, encoding, max_bytes, metadata)?,
+        None => (scaled.encode_with_metadata(format, encoding, metadata)?, encoding.quality),
+    };
+    if let Some(max_bytes) = max_bytes.filter(|max| encoding.is_lossy(format) && buf.len() as u64 > *max) {
+        if !opts.is_quiet { eprintln!("WARNING: {:?} is {} bytes at quality 1, over the budget of {}", img_path, buf.len(), max_bytes) }
+    }
 
-        if opts.is_verbose {print_image_details(&scaled, &img_path)}
-    } else {
-        if opts.is_verbose { println!(">> {:?}", img_path);}
+    if !opts.is_hashed {
+        std::fs::write(&img_path, &buf)?;
+        return Ok((img_path, quality));
     }
 
-    Ok(())
+    let hash = blake3::hash(&buf).to_hex();
+
+    let stem = file_stem(&img_path)?;
+    let ext = img_path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let hashed = img_path.with_file_name(format!("{}.{}.{}", stem, &hash[..HASH_LEN], ext));
+
+    std::fs::write(&hashed, &buf)?;
+    Ok((hashed, quality))
 }
 
 /// Return an array that is suitable for large and small images based on the provided max width
-fn strip_sizes(max: u32, sizes: &Vec<u32>) -> Option<Vec<u32>>
+fn strip_sizes(max: u32, sizes: &[u32]) -> Option<Vec<u32>>
 {
-   let mut vec = vec![];
-   for x in 0 .. sizes.len() {
-       if max >= sizes[x] {
-           vec.push(sizes[x])
-       }
-   }
+   let vec: Vec<u32> = sizes.iter().copied().filter(|sz| max >= *sz).collect();
    // return None if empty vec
-   if vec.len() == 0 {
-       return None;}
+   if vec.is_empty() {
+       None}
    else {
        Some(vec)}
 }
 
-/// Provide an <img srcset=""> tag with the image names, smaller images get smaller sets of images
-fn create_tag<'a>(max: u32, f: &'a str, ext: &'a str, n: &'a str, sizes: &Vec<u32>) -> String
+/// Render the markup for the image with the template of the options; by default an <img srcset=""> tag with the
+/// image names, smaller images get smaller sets of images. Given variants in more than one format, a <picture>
+/// with a <source> for each of the others falls back to the <img> in the format of the legacy image.
+/// Density variants are described as `1x`, `2x` ... and need no sizes attribute. The variants of each crop come
+/// first, as a <source> per format with the media condition of the crop.
+///
+/// `f` is the directory (with any prefix) holding the images, `n` the original filename, `alt` the alt text
+/// and `sizes` the sizes attribute.
+pub fn create_tag(f: &str, legacy: &Variant, variants: &[Variant], n: &str, alt: &str, sizes: &str, opts: &Opts) -> Result<String>
 {
+    let (main, cropped): (Vec<Variant>, Vec<Variant>) = variants.iter().cloned().partition(|v| v.crop.is_none());
 
-    let mut string = String::new();
-    for x in 0 ..sizes.len() {
-        if max >= sizes[x] {
-            let s =
-            match x {
-                0 => format!("{0}/{1}w.{2} {1}w",f, sizes[x], ext),
-                _ => format!(",{0}/{1}w.{2} {1}w",f, sizes[x], ext),
-            };
-            string.push_str(&s);
+    // The other formats in the order first seen
+    let mut others: Vec<ImageFormat> = vec![];
+    for v in &main {
+        if v.format != legacy.format && !others.contains(&v.format) {
+            others.push(v.format);
         }
     }
- 
-    let tag =
-    match max {
-        d if d < 480 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:480px) 100vw, min-width:481px) 25vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 640 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:640px) 100vw, min-width:641px) 33vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 768 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:320px) 50vw, (max-width:768px) 100vw, min-width:769px) 50vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 960 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:320px) 50vw, (max-width:960px) 75vw, min-width:961px) 95vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 1024 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:320px) 50vw, (max-width:960px) 75vw, min-width:961px) 95vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 1366 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:320px) 50vw, (max-width:960px) 75vw, (min-width:961px) 95vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        d if d < 1660 => format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(max-width:320px) 25vw, (min-width: 960px) 75vw, 100vw\" alt=\"A file named {2}\">",f, ext, n, string),
-        _ =>             format!("<img src=\"{0}/legacy.{1}\" srcset=\"{3}\" sizes=\"(min-width: 1024px) 50vw, 100vw\" alt=\"A file named {2}\">",f, ext, n, string),
+
+    // Browsers take the first <source> they support, so offer the smallest encodings first
+    others.sort_by_key(|format| match format {
+        ImageFormat::Avif => 0,
+        ImageFormat::WebP => 1,
+        _ => 2,
+    });
+
+    // Browsers take the first <source> that matches, so the crops go before the other formats
+    let mut sources = vec![];
+    for crop in &opts.crops {
+        let variants: Vec<Variant> = cropped.iter().filter(|v| v.crop.as_ref() == Some(&crop.name)).cloned().collect();
+        let Some(largest) = variants.iter().max_by_key(|v| v.width) else { continue };
+        for format in others.iter().chain(std::iter::once(&legacy.format)) {
+            sources.push(TagSource {
+                format: format_name(*format),
+                mime: mime_type(*format),
+                srcset: srcset_attr(f, *format, &variants),
+                media: Some(crop.media.clone()),
+                width: largest.width / largest.density.unwrap_or(1),
+                height: largest.height / largest.density.unwrap_or(1),
+            });
+        }
+    }
+    sources.extend(others.into_iter().map(|other| TagSource {
+        format: format_name(other),
+        mime: mime_type(other),
+        srcset: srcset_attr(f, other, &main),
+        media: None,
+        width: legacy.width / legacy.density.unwrap_or(1),
+        height: legacy.height / legacy.density.unwrap_or(1),
+    }));
+
+    let data = TagData {
+        dir: f.to_string(),
+        name: n.to_string(),
+        alt: alt.to_string(),
+        src: format!("{}/{}", f, legacy.file_name()),
+        width: legacy.width / legacy.density.unwrap_or(1),
+        height: legacy.height / legacy.density.unwrap_or(1),
+        loading: opts.loading.clone(),
+        decoding: opts.decoding.clone(),
+        format: format_name(legacy.format),
+        mime: mime_type(legacy.format),
+        srcset: srcset_attr(f, legacy.format, &main),
+        sizes: match legacy.density {
+            Some(_) => None,
+            None => Some(sizes.to_string()),
+        },
+        sources,
+        variants: variants.iter().map(|v| TagVariant {
+            url: format!("{}/{}", f, v.file_name()),
+            width: v.width,
+            height: v.height,
+            bytes: v.bytes,
+            format: format_name(v.format),
+            descriptor: v.descriptor(),
+            crop: v.crop.clone(),
+        }).collect(),
     };
 
-    tag
+    opts.template.render(&data)
 }
 
-
-fn print_image_details(img: &DynamicImage, path: &Path) {
-    let (w,h) = img.dimensions();
-    let sz = path.metadata().unwrap().len();
-    println!("{:?} Width={}: Height={}; Size={}; Color={:?}", path, Paint::red(w), h, Paint::red(human_bytes::human_bytes(sz as f64)), img.color());
+/// The srcset attribute listing each variant of the format.
+fn srcset_attr(f: &str, format: ImageFormat, variants: &[Variant]) -> String
+{
+    let mut string = String::new();
+    for (x, v) in variants.iter().filter(|v| v.format == format).enumerate() {
+        let s =
+        match x {
+            0 => format!("{0}/{1} {2}",f, v.file_name(), v.descriptor()),
+            _ => format!(",{0}/{1} {2}",f, v.file_name(), v.descriptor()),
+        };
+        string.push_str(&s);
+    }
+    string
 }
 
-
-
-/*
-fn encode_image_jpeg(path: &Path, img: &DynamicImage, nwidth: u32, nheight: u32) -> Result<()> 
+/// The sizes attribute given in the options, or else picked by the width of the legacy image.
+fn tag_sizes(max: u32, opts: &Opts) -> String
 {
+    match &opts.sizes_attr {
+        Some(sizes_attr) => sizes_attr.clone(),
+        None => default_sizes_attr(max).to_string(),
+    }
+}
 
-    let fin = File::open(path)?;
-    let buf = BufReader::new(fin);
+/// The sizes attribute for the width of the legacy image.
+fn default_sizes_attr(max: u32) -> &'static str
+{
+    match max {
+        d if d < 480 => "(max-width:480px) 100vw, (min-width:481px) 25vw",
+        d if d < 640 => "(max-width:640px) 100vw, (min-width:641px) 33vw",
+        d if d < 768 => "(max-width:320px) 50vw, (max-width:768px) 100vw, (min-width:769px) 50vw",
+        d if d < 960 => "(max-width:320px) 50vw, (max-width:960px) 75vw, (min-width:961px) 95vw",
+        d if d < 1024 => "(max-width:320px) 50vw, (max-width:960px) 75vw, (min-width:961px) 95vw",
+        d if d < 1366 => "(max-width:320px) 50vw, (max-width:960px) 75vw, (min-width:961px) 95vw",
+        d if d < 1660 => "(max-width:320px) 25vw, (min-width: 960px) 75vw, 100vw",
+        _ =>             "(min-width: 1024px) 50vw, 100vw",
+    }
+}
 
+/// The lowercase name of a format, such as `jpeg` or `webp`.
+fn format_name(format: ImageFormat) -> String
+{
+    format!("{:?}", format).to_lowercase()
+}
 
-    let mut encoder = image::jpeg::JpegEncoder::new_with_quality(&mut buf, 80);
+/// The MIME type for the `type` attribute of a <source>.
+fn mime_type(format: ImageFormat) -> String
+{
+    match format {
+        ImageFormat::Jpeg => "image/jpeg".to_string(),
+        ImageFormat::Tiff => "image/tiff".to_string(),
+        ImageFormat::Ico => "image/x-icon".to_string(),
+        ImageFormat::Pnm => "image/x-portable-anymap".to_string(),
+        _ => format!("image/{}", format.extensions_str()[0]),
+    }
+}
 
-//    let img = image::ImageBuffer::new(512, 512);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharpens_half_again_for_each_halving() {
+        let opts = Opts { sigma: 1.0, is_sharpen_by_ratio: true, ..Opts::default() };
+        let sigma = |width| unsharpen_for(width, 320, &opts).unwrap().0;
+        assert_eq!(sigma(320), 1.0);
+        assert!((sigma(640) - 1.5).abs() < 1e-5);
+        assert!((sigma(1280) - 2.25).abs() < 1e-5);
+    }
 
-    let bytes = img.as_bytes();
+    /// An empty directory of the test's own under the system temp directory.
+    #[cfg(feature = "png")]
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("srcset-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-    encoder.encode(&bytes, nwidth, nheight, image::ColorType::Rgba8)?;
+    #[cfg(feature = "png")]
+    #[test]
+    fn redoes_plain_outputs_once_hashed() {
+        let dir = scratch("hashed");
+        let source = dir.join("photo.png");
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 300, |x, y| image::Rgb([x as u8, y as u8, 0])))
+            .save(&source).unwrap();
+        let out = dir.join("out");
+
+        for (incremental, cached) in [(true, false), (false, true)] {
+            let _ = std::fs::remove_dir_all(&out);
+            let run = |hashed: bool| {
+                let opts = Opts::builder(&source).outpath(&out).sizes(vec![320]).quiet(true)
+                    .incremental(incremental).cache(cached).hashed(hashed).build().unwrap();
+                let processed = process_image(&source, &opts, &mut Metrics::default()).unwrap();
+                if let Some(cache) = &opts.cache {
+                    cache.save().unwrap();
+                }
+                processed
+            };
 
-    buffer.write_all(bytes)?;
+            assert!(!run(false).up_to_date);
+            assert!(run(false).up_to_date);
 
-    Ok(())
+            let hashed = run(true);
+            assert!(!hashed.up_to_date);
+            assert!(hashed.variants.iter().chain([&hashed.legacy]).all(|v| v.file_name().split('.').count() == 3), "{:?}", hashed.variants);
+            assert!(!hashed.tag.contains("/320w.png"), "{}", hashed.tag);
+            assert!(run(true).up_to_date);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
-*/
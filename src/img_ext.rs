@@ -3,19 +3,24 @@
 
 use std::path::Path;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Cursor, Seek, Write};
 
 use image::ColorType;
 use image::error::{ImageError, ImageFormatHint, ImageResult};
-use image::{ImageFormat, ImageEncoder};
-use image::EncodableLayout;
+use image::ImageFormat;
+#[cfg(any(feature = "bmp", feature = "ico", feature = "jpeg", feature = "png", feature = "pnm",
+    feature = "farbfeld", feature = "tiff", feature = "tga", feature = "webp-encoder"))]
+use image::ImageEncoder;
+#[cfg(any(feature = "png", feature = "webp"))]
+use img_parts::Bytes;
+
+use crate::metadata::Metadata;
+use crate::ssim::ssim;
 
 #[cfg(feature = "bmp")]
 use image::codecs::bmp;
 #[cfg(feature = "gif")]
 use image::codecs::gif;
-#[cfg(feature = "hdr")]
-use image::codecs::hdr;
 #[cfg(feature = "ico")]
 use image::codecs::ico;
 #[cfg(feature = "jpeg")]
@@ -26,26 +31,69 @@ use image::codecs::png;
 use image::codecs::pnm;
 #[cfg(feature = "tga")]
 use image::codecs::tga;
-#[cfg(feature = "dds")]
-use image::codecs::dds;
 #[cfg(feature = "tiff")]
 use image::codecs::tiff;
-#[cfg(feature = "webp")]
+#[cfg(feature = "webp-encoder")]
 use image::codecs::webp;
 #[cfg(feature = "farbfeld")]
 use image::codecs::farbfeld;
-#[cfg(any(feature = "avif-encoder", feature = "avif-decoder"))]
-use image::codecs::avif;
 
 
+/// The AVIF encoder speed when none is given, that of `cavif`.
+pub const DEFAULT_AVIF_SPEED: u8 = 4;
+
+/// How an image is encoded: the quality of the lossy formats, whether WebP is lossless and the
+/// speed of the AVIF encoder. A bare quality converts into a lossy encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    /// In the range 1-100 where 100 is the best.
+    pub quality: u8,
+    /// Encode WebP losslessly, ignoring the quality.
+    pub lossless: bool,
+    /// In the range 1-10 where 1 is the slowest and smallest.
+    pub avif_speed: u8,
+}
+
+impl From<u8> for Encoding {
+    fn from(quality: u8) -> Self {
+        Encoding { quality, lossless: false, avif_speed: DEFAULT_AVIF_SPEED }
+    }
+}
+
+impl Encoding {
+    /// The same encoding at another quality.
+    pub fn with_quality(self, quality: u8) -> Self {
+        Encoding { quality, ..self }
+    }
+
+    /// Whether the encoder of the format takes the quality, which the others ignore.
+    pub fn is_lossy(&self, format: ImageFormat) -> bool {
+        match format {
+            ImageFormat::Jpeg | ImageFormat::Avif => true,
+            ImageFormat::WebP => !self.lossless,
+            _ => false,
+        }
+    }
+}
+
+
+/// Save a `DynamicImage` while passing the quality through to the encoder.
 pub trait ImgExt {
-    fn save_with_quality<Q>(&self, path: Q, quality: u8) -> ImageResult<()>
+    fn save_with_quality<Q>(&self, path: Q, quality: impl Into<Encoding>) -> ImageResult<()>
     where
         Q: AsRef<Path>;
 
-    fn save_safe_with_quality<Q>(&self, path: Q, quality: u8) -> ImageResult<()>
+    fn save_safe_with_quality<Q>(&self, path: Q, quality: impl Into<Encoding>) -> ImageResult<()>
         where
             Q: AsRef<Path>;        
+
+    fn encode_safe_with_quality(&self, format: ImageFormat, quality: impl Into<Encoding>) -> ImageResult<Vec<u8>>;
+
+    fn encode_with_metadata(&self, format: ImageFormat, quality: impl Into<Encoding>, metadata: &Metadata) -> ImageResult<Vec<u8>>;
+
+    fn encode_within(&self, format: ImageFormat, quality: impl Into<Encoding>, max_bytes: u64, metadata: &Metadata) -> ImageResult<(Vec<u8>, u8)>;
+
+    fn quality_for_ssim(&self, format: ImageFormat, encoding: Encoding, threshold: f64) -> ImageResult<u8>;
 }
 
 
@@ -55,7 +103,7 @@ impl ImgExt for image::DynamicImage {
     ///
     /// The image format is derived from the file extension.
     /// Currently only jpeg and png files are supported.
-    fn save_with_quality<Q>(&self, path: Q, quality: u8) -> ImageResult<()>
+    fn save_with_quality<Q>(&self, path: Q, quality: impl Into<Encoding>) -> ImageResult<()>
     where
         Q: AsRef<Path>,
     {
@@ -71,18 +119,18 @@ impl ImgExt for image::DynamicImage {
         )
     }
 
-    fn save_safe_with_quality<Q>(&self, path: Q, quality: u8) -> ImageResult<()>
+    /// Saves as `save_with_quality`, first converting to the 8 bit RGB or RGBA that WebP and AVIF take.
+    fn save_safe_with_quality<Q>(&self, path: Q, quality: impl Into<Encoding>) -> ImageResult<()>
         where
             Q: AsRef<Path>,
     {
         let format =  ImageFormat::from_path(&path)?;
 
         match format {
-            #[cfg(feature = "webp")]
-            image::ImageFormat::WebP => {
-                let rgb_image = self.clone().into_rgb8();
-                let buf = rgb_image.as_bytes();            
-                save_buffer_with_format_quality(path.as_ref(), buf, self.width(), self.height(), ColorType::Rgb8, format, quality)
+            #[cfg(any(feature = "webp-encoder", feature = "avif-encoder"))]
+            image::ImageFormat::WebP | image::ImageFormat::Avif => {
+                let (buf, color) = rgb_buffer(self);
+                save_buffer_with_format_quality(path.as_ref(), &buf, self.width(), self.height(), color, format, quality)
             },
 
             _ => save_buffer_with_quality(path.as_ref(),
@@ -94,6 +142,224 @@ impl ImgExt for image::DynamicImage {
 
     }
 
+    /// Encodes into memory rather than a file, with the same conversions as `save_safe_with_quality`.
+    fn encode_safe_with_quality(&self, format: ImageFormat, quality: impl Into<Encoding>) -> ImageResult<Vec<u8>>
+    {
+        let mut fout = Cursor::new(Vec::new());
+
+        match format {
+            #[cfg(any(feature = "webp-encoder", feature = "avif-encoder"))]
+            image::ImageFormat::WebP | image::ImageFormat::Avif => {
+                let (buf, color) = rgb_buffer(self);
+                write_buffer_with_format_quality(&mut fout, &buf, self.width(), self.height(), color, format, quality)?
+            },
+
+            _ => write_buffer_with_format_quality(&mut fout,
+                self.as_bytes(), self.width(), self.height(), self.color(), format, quality
+            )?
+        }
+
+        Ok(fout.into_inner())
+    }
+
+    /// Encodes into memory as `encode_safe_with_quality`, then embeds the metadata.
+    fn encode_with_metadata(&self, format: ImageFormat, quality: impl Into<Encoding>, metadata: &Metadata) -> ImageResult<Vec<u8>>
+    {
+        let buf = self.encode_safe_with_quality(format, quality)?;
+        embed_metadata(buf, format, metadata)
+    }
+
+    /// Encodes with the metadata at the highest quality up to `quality` that fits within `max_bytes`,
+    /// by a binary search, returning the buffer and the quality chosen. When none fits the lowest is
+    /// returned; a lossless encoding is encoded once.
+    fn encode_within(&self, format: ImageFormat, quality: impl Into<Encoding>, max_bytes: u64, metadata: &Metadata) -> ImageResult<(Vec<u8>, u8)>
+    {
+        let encoding = quality.into();
+        let best = self.encode_with_metadata(format, encoding, metadata)?;
+        if !encoding.is_lossy(format) || best.len() as u64 <= max_bytes || encoding.quality <= 1 {
+            return Ok((best, encoding.quality));
+        }
+
+        // The smallest known not to fit and the largest that does, if any
+        let (mut lo, mut hi) = (1, encoding.quality);
+        let mut best = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let buf = self.encode_with_metadata(format, encoding.with_quality(mid), metadata)?;
+            if buf.len() as u64 <= max_bytes {
+                best = Some((buf, mid));
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        match best {
+            Some(best) => Ok(best),
+            None => Ok((self.encode_with_metadata(format, encoding.with_quality(1), metadata)?, 1)),
+        }
+    }
+
+    /// The lowest quality whose encoding, decoded again, has an SSIM to the image of at least the
+    /// threshold, by a binary search of 1-100; 100 if none does. A lossless encoding gives 100, and
+    /// an AVIF, which there is no decoder for, keeps the quality of the encoding.
+    fn quality_for_ssim(&self, format: ImageFormat, encoding: Encoding, threshold: f64) -> ImageResult<u8>
+    {
+        if !encoding.is_lossy(format) {
+            return Ok(100);
+        }
+        if format == ImageFormat::Avif {
+            return Ok(encoding.quality);
+        }
+
+        let (mut lo, mut hi) = (1, 100);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let decoded = image::load_from_memory_with_format(&self.encode_safe_with_quality(format, encoding.with_quality(mid))?, format)?;
+            if ssim(self, &decoded) >= threshold {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(lo)
+    }
+
+}
+
+
+/// The 8 bit RGB, or RGBA when there is any alpha, that the WebP and AVIF encoders take.
+#[cfg(any(feature = "webp-encoder", feature = "avif-encoder"))]
+fn rgb_buffer(img: &image::DynamicImage) -> (Vec<u8>, ColorType) {
+    match img.color().has_alpha() {
+        true => (img.to_rgba8().into_raw(), ColorType::Rgba8),
+        false => (img.to_rgb8().into_raw(), ColorType::Rgb8),
+    }
+}
+
+
+/// Embed the EXIF, XMP and ICC profile into an encoded JPEG, PNG or WebP. Any other format is
+/// returned as is.
+#[allow(unused_variables, unreachable_code)]
+pub fn embed_metadata(buf: Vec<u8>, format: ImageFormat, metadata: &Metadata) -> ImageResult<Vec<u8>> {
+    if metadata.is_empty() {
+        return Ok(buf);
+    }
+
+    let bytes: img_parts::Result<Vec<u8>> = match format {
+        #[cfg(feature = "jpeg")]
+        image::ImageFormat::Jpeg => embed_jpeg(buf, metadata),
+
+        #[cfg(feature = "png")]
+        image::ImageFormat::Png => embed_png(buf, metadata),
+
+        #[cfg(feature = "webp")]
+        image::ImageFormat::WebP => embed_webp(buf, metadata),
+
+        _ => return Ok(buf),
+    };
+
+    bytes.map_err(|e| ImageError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())))
+}
+
+/// The EXIF, XMP and ICC segments go straight after the JFIF APP0, the ICC profile split across
+/// as many APP2 segments as it needs.
+#[cfg(feature = "jpeg")]
+fn embed_jpeg(buf: Vec<u8>, metadata: &Metadata) -> img_parts::Result<Vec<u8>> {
+    use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+
+    const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+    const ICC_CHUNK: usize = 65519 - ICC_PREFIX.len() - 2;
+
+    let mut jpeg = Jpeg::from_bytes(buf.into())?;
+
+    let mut segments = vec![];
+    if let Some(exif) = &metadata.exif {
+        segments.push(JpegSegment::new_with_contents(markers::APP1, [crate::metadata::EXIF_PREFIX, exif].concat().into()));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        segments.push(JpegSegment::new_with_contents(markers::APP1, [XMP_PREFIX, xmp].concat().into()));
+    }
+    if let Some(icc) = &metadata.icc {
+        let chunks: Vec<&[u8]> = icc.chunks(ICC_CHUNK).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let seq = [i as u8 + 1, chunks.len() as u8];
+            segments.push(JpegSegment::new_with_contents(markers::APP2, [ICC_PREFIX, &seq, chunk].concat().into()));
+        }
+    }
+
+    let at = jpeg.segments().iter().take_while(|s| s.marker() == markers::APP0).count();
+    jpeg.segments_mut().splice(at..at, segments);
+    Ok(jpeg.encoder().bytes().to_vec())
+}
+
+/// The iCCP, eXIf and XMP iTXt chunks go straight after the IHDR, before the image data.
+#[cfg(feature = "png")]
+fn embed_png(buf: Vec<u8>, metadata: &Metadata) -> img_parts::Result<Vec<u8>> {
+    use img_parts::png::{Png, PngChunk};
+    use img_parts::ImageICC;
+
+    // The keyword, then uncompressed with neither language nor translated keyword
+    const XMP_PREFIX: &[u8] = b"XML:com.adobe.xmp\0\0\0\0\0";
+
+    let mut png = Png::from_bytes(buf.into())?;
+
+    let mut chunks = vec![];
+    if let Some(exif) = &metadata.exif {
+        chunks.push(PngChunk::new(*b"eXIf", Bytes::copy_from_slice(exif)));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        chunks.push(PngChunk::new(*b"iTXt", [XMP_PREFIX, xmp].concat().into()));
+    }
+    png.chunks_mut().splice(1..1, chunks);
+
+    png.set_icc_profile(metadata.icc.as_deref().map(Bytes::copy_from_slice));
+    Ok(png.encoder().bytes().to_vec())
+}
+
+/// The extended format: a VP8X header flagging the metadata, the ICCP chunk, the image, then the
+/// EXIF and XMP chunks.
+#[cfg(feature = "webp")]
+fn embed_webp(buf: Vec<u8>, metadata: &Metadata) -> img_parts::Result<Vec<u8>> {
+    use img_parts::riff::{RiffChunk, RiffContent};
+    use img_parts::webp::{WebP, CHUNK_ALPH, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP};
+
+    let webp = WebP::from_bytes(buf.into())?;
+
+    // img-parts reads the canvas of a VP8X from the wrong offset, so read it here
+    let canvas = webp.chunk_by_id(CHUNK_VP8X).and_then(|c| c.content().data()).and_then(|d| d.get(4..10))
+        .map(|d| (u32::from_le_bytes([d[0], d[1], d[2], 0]) + 1, u32::from_le_bytes([d[3], d[4], d[5], 0]) + 1));
+    let (width, height) = canvas.or_else(|| webp.dimensions()).ok_or(img_parts::Error::Truncated)?;
+
+    let image: Vec<RiffChunk> = webp.chunks().iter()
+        .filter(|c| ![CHUNK_VP8X, CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP].contains(&c.id()))
+        .cloned()
+        .collect();
+
+    // A lossless image flags its alpha in the VP8L header
+    let alpha = image.iter().any(|c| c.id() == CHUNK_ALPH || (c.id() == CHUNK_VP8L
+        && c.content().data().and_then(|d| d.get(4)).is_some_and(|b| b & 0x10 != 0)));
+
+    let mut flags = 0u8;
+    if metadata.icc.is_some() { flags |= 0x20; }
+    if alpha { flags |= 0x10; }
+    if metadata.exif.is_some() { flags |= 0x08; }
+    if metadata.xmp.is_some() { flags |= 0x04; }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let data = |id, bytes: &[u8]| RiffChunk::new(id, RiffContent::Data(Bytes::copy_from_slice(bytes)));
+    let mut chunks = vec![data(CHUNK_VP8X, &vp8x)];
+    chunks.extend(metadata.icc.as_deref().map(|icc| data(CHUNK_ICCP, icc)));
+    chunks.extend(image);
+    chunks.extend(metadata.exif.as_deref().map(|exif| data(CHUNK_EXIF, exif)));
+    chunks.extend(metadata.xmp.as_deref().map(|xmp| data(CHUNK_XMP, xmp)));
+
+    let riff = RiffChunk::new(*b"RIFF", RiffContent::List { kind: Some(*b"WEBP"), subchunks: chunks });
+    Ok(riff.encoder().bytes().to_vec())
 }
 
 
@@ -110,9 +376,8 @@ pub fn save_buffer_with_quality(
     width: u32,
     height: u32,
     color: ColorType,
-    quality: u8
+    quality: impl Into<Encoding>
 ) -> ImageResult<()> {
-    let fout = &mut BufWriter::new(File::create(path)?);
     let format =  ImageFormat::from_path(path)?;
     save_buffer_with_format_quality(path, buf, width, height, color, format, quality)
 }
@@ -126,23 +391,11 @@ pub fn save_buffer_with_format_quality(
     height: u32,
     color: ColorType,
     format: ImageFormat,
-    quality: u8
+    quality: impl Into<Encoding>
 ) -> ImageResult<()> {
     let fout = &mut BufWriter::new(File::create(path)?);
 
     match format {
-       #[cfg(feature = "gif")]
-        image::ImageFormat::Gif => gif::GifEncoder::new(fout).encode(buf, width, height, color),
-
-        #[cfg(feature = "ico")]
-        image::ImageFormat::Ico => ico::IcoEncoder::new(fout).write_image(buf, width, height, color),
-
-        #[cfg(feature = "jpeg")]
-        image::ImageFormat::Jpeg => jpeg::JpegEncoder::new_with_quality(fout, quality).write_image(buf, width, height, color),
-
-        #[cfg(feature = "png")]
-        image::ImageFormat::Png => png::PngEncoder::new(fout).write_image(buf, width, height, color),
-
         #[cfg(feature = "pnm")]
         image::ImageFormat::Pnm => {
             let ext = path.extension()
@@ -150,24 +403,58 @@ pub fn save_buffer_with_format_quality(
             .map_or("".to_string(), |s| s.to_ascii_lowercase());
             match &*ext {
                 "pbm" => pnm::PnmEncoder::new(fout)
-                    .with_subtype(pnm::PNMSubtype::Bitmap(pnm::SampleEncoding::Binary))
+                    .with_subtype(pnm::PnmSubtype::Bitmap(pnm::SampleEncoding::Binary))
                     .write_image(buf, width, height, color),
                 "pgm" => pnm::PnmEncoder::new(fout)
-                    .with_subtype(pnm::PNMSubtype::Graymap(pnm::SampleEncoding::Binary))
+                    .with_subtype(pnm::PnmSubtype::Graymap(pnm::SampleEncoding::Binary))
                     .write_image(buf, width, height, color),
                 "ppm" => pnm::PnmEncoder::new(fout)
-                    .with_subtype(pnm::PNMSubtype::Pixmap(pnm::SampleEncoding::Binary))
+                    .with_subtype(pnm::PnmSubtype::Pixmap(pnm::SampleEncoding::Binary))
                     .write_image(buf, width, height, color),
                 "pam" => pnm::PnmEncoder::new(fout).write_image(buf, width, height, color),
                 _ => Err(ImageError::Unsupported(ImageFormatHint::Exact(format).into())), // Unsupported Pnm subtype.
             }
         },
 
+        _ => write_buffer_with_format_quality(fout, buf, width, height, color, format, quality),
+    }
+}
+
+
+/// Encode the buffer onto any writer. Without a filename to pick the subtype, Pnm is written
+/// as whichever subtype suits the color type.
+#[allow(unused_variables)]
+pub fn write_buffer_with_format_quality<W: Write + Seek>(
+    fout: &mut W,
+    buf: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: ImageFormat,
+    quality: impl Into<Encoding>
+) -> ImageResult<()> {
+    let encoding = quality.into();
+    match format {
+       #[cfg(feature = "gif")]
+        image::ImageFormat::Gif => gif::GifEncoder::new(fout).encode(buf, width, height, color),
+
+        #[cfg(feature = "ico")]
+        image::ImageFormat::Ico => ico::IcoEncoder::new(fout).write_image(buf, width, height, color),
+
+        #[cfg(feature = "jpeg")]
+        image::ImageFormat::Jpeg => jpeg::JpegEncoder::new_with_quality(fout, encoding.quality).write_image(buf, width, height, color),
+
+        #[cfg(feature = "png")]
+        image::ImageFormat::Png => png::PngEncoder::new(fout).write_image(buf, width, height, color),
+
+        #[cfg(feature = "pnm")]
+        image::ImageFormat::Pnm => pnm::PnmEncoder::new(fout).write_image(buf, width, height, color),
+
         #[cfg(feature = "farbfeld")]
         image::ImageFormat::Farbfeld => farbfeld::FarbfeldEncoder::new(fout).write_image(buf, width, height, color),        
 
         #[cfg(feature = "avif-encoder")]
-        image::ImageFormat::Avif => avif::AvifEncoder::new(fout).write_image(buf, width, height, color),
+        image::ImageFormat::Avif => fout.write_all(&encode_avif(buf, width, height, color, encoding)?).map_err(ImageError::IoError),
         //#[cfg(feature = "hdr")]
         //image::ImageFormat::Hdr => hdr::HdrEncoder::new(fout).encode(&[Rgb<f32>], width, height), // usize
 
@@ -181,19 +468,50 @@ pub fn save_buffer_with_format_quality(
        #[cfg(feature = "tga")]
         image::ImageFormat::Tga => tga::TgaEncoder::new(fout).write_image(buf, width, height, color),
 
-        #[cfg(feature = "webp")]
+        #[cfg(feature = "webp-encoder")]
         image::ImageFormat::WebP => {
-            //let webp = webp::WebPEncoder::new(fout);
-            let r = webp::WebPEncoder::new(fout).write_image(buf, width, height, ColorType::Rgb8);
-            println!("Webp result {:?}", r);
-            r
-        }, 
+            let quality = match encoding.lossless {
+                true => webp::WebPQuality::lossless(),
+                false => webp::WebPQuality::lossy(encoding.quality),
+            };
+            webp::WebPEncoder::new_with_quality(fout, quality).write_image(buf, width, height, color)
+        },
 
         format => Err(ImageError::Unsupported(ImageFormatHint::Exact(format).into())),
     }
 }
 
 
+/// Encode 8 bit RGB or RGBA as AVIF with ravif; the alpha, if any is not opaque, is kept at the
+/// same quality as the colour.
+#[cfg(feature = "avif-encoder")]
+fn encode_avif(buf: &[u8], width: u32, height: u32, color: ColorType, encoding: Encoding) -> ImageResult<Vec<u8>> {
+    use image::error::{EncodingError, UnsupportedError, UnsupportedErrorKind};
+    use ravif::{Encoder, Img, RGB8, RGBA8};
+
+    let encoder = Encoder::new()
+        .with_quality(encoding.quality.clamp(1, 100) as f32)
+        .with_alpha_quality(encoding.quality.clamp(1, 100) as f32)
+        .with_speed(encoding.avif_speed.clamp(1, 10));
+    let (width, height) = (width as usize, height as usize);
+
+    let encoded = match color {
+        ColorType::Rgb8 => {
+            let pixels: Vec<RGB8> = buf.chunks_exact(3).map(|p| RGB8::new(p[0], p[1], p[2])).collect();
+            encoder.encode_rgb(Img::new(&pixels[..], width, height))
+        },
+        ColorType::Rgba8 => {
+            let pixels: Vec<RGBA8> = buf.chunks_exact(4).map(|p| RGBA8::new(p[0], p[1], p[2], p[3])).collect();
+            encoder.encode_rgba(Img::new(&pixels[..], width, height))
+        },
+        _ => return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormat::Avif.into(), UnsupportedErrorKind::Color(color.into())))),
+    };
+
+    encoded.map(|e| e.avif_file).map_err(|e| ImageError::Encoding(EncodingError::new(ImageFormat::Avif.into(), e)))
+}
+
+
 /*
 #[cfg(feature = "ico")]
 image::ImageFormat::Ico => self.save_with_quality(path,quality),
@@ -223,4 +541,171 @@ image::ImageFormat::WebP => {
 
     save_buffer_with_format_quality(path.as_ref(), buf, self.width(), self.height(), ColorType::Rgb8, format, quality)
 }, 
-*/
\ No newline at end of file
+*/
+#[cfg(all(test, feature = "jpeg"))]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    /// A noisy image, which unlike a flat one takes more bytes at each step of quality.
+    fn noise() -> DynamicImage {
+        let mut seed = 1u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |_, _| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let [r, g, b, _] = seed.to_be_bytes();
+            image::Rgb([r, g, b])
+        }))
+    }
+
+    #[test]
+    fn keeps_the_quality_within_the_budget() {
+        let img = noise();
+        let (buf, quality) = img.encode_within(ImageFormat::Jpeg, 90, u64::MAX, &Metadata::default()).unwrap();
+        assert_eq!(quality, 90);
+        assert_eq!(buf, img.encode_safe_with_quality(ImageFormat::Jpeg, 90).unwrap());
+    }
+
+    #[test]
+    fn lowers_the_quality_to_fit_the_budget() {
+        let img = noise();
+        let budget = img.encode_safe_with_quality(ImageFormat::Jpeg, 50).unwrap().len() as u64;
+        let (buf, quality) = img.encode_within(ImageFormat::Jpeg, 90, budget, &Metadata::default()).unwrap();
+        assert!(buf.len() as u64 <= budget);
+        assert!((50..90).contains(&quality), "{}", quality);
+        let above = img.encode_safe_with_quality(ImageFormat::Jpeg, quality + 1).unwrap();
+        assert!(above.len() as u64 > budget);
+    }
+
+    #[test]
+    fn falls_back_to_the_lowest_quality_over_the_budget() {
+        let img = noise();
+        let (buf, quality) = img.encode_within(ImageFormat::Jpeg, 90, 100, &Metadata::default()).unwrap();
+        assert_eq!(quality, 1);
+        assert_eq!(buf, img.encode_safe_with_quality(ImageFormat::Jpeg, 1).unwrap());
+        assert!(buf.len() > 100);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn encodes_a_lossless_type_once() {
+        let img = noise();
+        let (buf, quality) = img.encode_within(ImageFormat::Png, 90, 100, &Metadata::default()).unwrap();
+        assert_eq!(quality, 90);
+        assert_eq!(buf, img.encode_safe_with_quality(ImageFormat::Png, 90).unwrap());
+    }
+
+    fn ssim_at(img: &DynamicImage, quality: u8) -> f64 {
+        let buf = img.encode_safe_with_quality(ImageFormat::Jpeg, quality).unwrap();
+        ssim(img, &image::load_from_memory_with_format(&buf, ImageFormat::Jpeg).unwrap())
+    }
+
+    #[test]
+    fn finds_the_lowest_quality_reaching_the_ssim() {
+        let img = noise();
+        let quality = img.quality_for_ssim(ImageFormat::Jpeg, Encoding::from(82), 0.9).unwrap();
+        assert!(quality > 1 && quality < 100, "{}", quality);
+        assert!(ssim_at(&img, quality) >= 0.9);
+        assert!(ssim_at(&img, quality - 1) < 0.9);
+    }
+
+    #[test]
+    fn gives_the_most_quality_to_an_ssim_not_reached() {
+        let img = noise();
+        assert!(ssim_at(&img, 100) < 1.0);
+        assert_eq!(img.quality_for_ssim(ImageFormat::Jpeg, Encoding::from(82), 1.0).unwrap(), 100);
+    }
+
+    #[test]
+    fn gives_the_least_quality_to_an_ssim_always_reached() {
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, image::Rgb([128, 128, 128])));
+        assert_eq!(flat.quality_for_ssim(ImageFormat::Jpeg, Encoding::from(82), 0.9).unwrap(), 1);
+    }
+
+    #[cfg(feature = "webp-encoder")]
+    #[test]
+    fn gives_the_most_quality_to_lossless() {
+        let lossless = Encoding { lossless: true, ..Encoding::from(82) };
+        assert_eq!(noise().quality_for_ssim(ImageFormat::WebP, lossless, 0.9).unwrap(), 100);
+    }
+
+    /// An EXIF of the source, upright only when turned a quarter, with a GPS position.
+    fn source_exif() -> Vec<u8> {
+        use exif::{Field, In, Tag, Value};
+        let ascii = |s: &str| Value::Ascii(vec![s.as_bytes().to_vec()]);
+        let fields = [
+            Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) },
+            Field { tag: Tag::Make, ifd_num: In::PRIMARY, value: ascii("ACME") },
+            Field { tag: Tag::Artist, ifd_num: In::PRIMARY, value: ascii("Jane Doe") },
+            Field { tag: Tag::Copyright, ifd_num: In::PRIMARY, value: ascii("(c) Jane Doe") },
+            Field { tag: Tag::GPSLatitudeRef, ifd_num: In::PRIMARY, value: ascii("N") },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        fields.iter().for_each(|f| writer.push_field(f));
+        let mut out = Cursor::new(Vec::new());
+        writer.write(&mut out, false).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn carries_the_metadata_kept_by_each_policy() {
+        use crate::metadata::{xmp_packet, Policy};
+        use exif::{In, Tag};
+
+        // An odd length, so that a chunk of it needs padding
+        let xmp = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:Description tiff:Orientation="6"/></x:xmpmeta> "#.to_vec();
+        let icc = include_bytes!("../testdata/display-p3.icc").to_vec();
+        let source = std::env::temp_dir().join(format!("srcset-metadata-{}.jpg", std::process::id()));
+        let full = Metadata { exif: Some(source_exif()), xmp: Some(xmp), icc: None };
+        std::fs::write(&source, noise().encode_with_metadata(ImageFormat::Jpeg, 90, &full).unwrap()).unwrap();
+
+        #[cfg(any(feature = "png", feature = "webp-encoder"))]
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 48, |x, y| image::Rgba([x as u8, y as u8, 0, 128])));
+        #[allow(unused_mut)]
+        let mut outputs = vec![(ImageFormat::Jpeg, noise())];
+        #[cfg(feature = "png")]
+        outputs.push((ImageFormat::Png, rgba.clone()));
+        #[cfg(feature = "webp-encoder")]
+        outputs.extend([(ImageFormat::WebP, noise()), (ImageFormat::WebP, rgba.clone())]);
+
+        for policy in [Policy::Strip, Policy::Copyright, Policy::All] {
+            let metadata = Metadata { icc: Some(icc.clone()), ..Metadata::read(&source, policy) };
+            for (format, img) in &outputs {
+                let what = format!("{:?} {:?} {:?}", policy, format, img.color());
+                let buf = img.encode_with_metadata(*format, 80, &metadata).unwrap();
+
+                let decoded = image::load_from_memory_with_format(&buf, *format).expect(&what);
+                assert_eq!((decoded.width(), decoded.height()), (img.width(), img.height()), "{}", what);
+                assert_eq!(decoded.color().has_alpha(), img.color().has_alpha() && *format != ImageFormat::Jpeg, "{}", what);
+                if *format == ImageFormat::WebP {
+                    assert_eq!(u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize, buf.len() - 8, "{}", what);
+                }
+                let parts = img_parts::DynImage::from_bytes(img_parts::Bytes::copy_from_slice(&buf)).unwrap().unwrap();
+                assert_eq!(img_parts::ImageICC::icc_profile(&parts).as_deref(), Some(&icc[..]), "{}", what);
+
+                let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&buf)).ok();
+                let tags = |exif: &exif::Exif| exif.fields().map(|f| f.tag).collect::<Vec<_>>();
+                match policy {
+                    Policy::Strip => {
+                        assert!(exif.is_none(), "{}", what);
+                        assert!(xmp_packet(&buf).is_none(), "{}", what);
+                    },
+                    Policy::Copyright => {
+                        let exif = exif.expect(&what);
+                        assert_eq!(tags(&exif), [Tag::Artist, Tag::Copyright], "{}", what);
+                        assert!(xmp_packet(&buf).is_none(), "{}", what);
+                    },
+                    Policy::All => {
+                        let exif = exif.expect(&what);
+                        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).and_then(|f| f.value.get_uint(0));
+                        assert!(matches!(orientation, None | Some(1)), "{}", what);
+                        assert!(tags(&exif).contains(&Tag::Make) && tags(&exif).contains(&Tag::GPSLatitudeRef), "{}", what);
+                        let xmp = xmp_packet(&buf).expect(&what);
+                        let upright: &[u8] = br#"tiff:Orientation="1""#;
+                        assert!(xmp.windows(upright.len()).any(|w| w == upright), "{}", what);
+                    },
+                }
+            }
+        }
+        std::fs::remove_file(&source).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+
+/*!
+
+# NAME
+
+**srcset** -- generate multiple responsive images for web and mobile.
+
+## SUMMARY
+
+The srcset utility generates multiple (eight) scaled versions of an image at particular breakpoints
+those of 320,480,640,768,960,1024,1280,1440 pixels wide, the widths match common Mobile and widescreen viewports.
+
+It convert images from jpg, png, tiff formats; and outputs the needed <img> tag.
+
+## SYNOPSIS
+
+`./srset [-rjnvzh] [—t type] [-s sizes] [-o outpath] filename`
+
+`./srset [-rjnvzh] [—t type] [-s sizes] [-o outpath] file hierarchy`
+
+## DESCRIPTION
+
+A file path, whether filename or file hierarcy is required. Specify the path (file or file hierarchy) to generate images. The type of file path, whether file or file hierarchy is determined by srcset.
+
+The options are as follows:
+
+-r  --recurse   **recurse** the provided directory. ignored for single file.
+
+-o  --out       The **output** directory for the resized image. defaults to `/tmp/srcset/`; windows its `srcset`
+
+-t  --type      The **type** of image conversion (png, jpg, webp, ... ); defaults to the same type as the original image found in the input path. Several types in comma,separated form such as `avif,webp,jpg` encode every size in each type from one decode and output a `<picture>` tag; the last type is the `<img>` fallback.
+
+-s  --size      The **sizes** for responsive images in comma,separated,value form; defaults to `480, 640, 768, 960, 1024, 1366, 1600, 1920`.
+
+--sizes-attr    The **sizes** attribute of the tag, such as `"(min-width: 1024px) 50vw, 100vw"`; defaults to one picked by the width of the largest image. It is checked as a CSS sizes list of media conditions and lengths, and a malformed one is rejected before any image is written.
+
+--template      The **template** of the markup written to `srcset.txt`: `html` (the default), `jsx`, `markdown`, `liquid`, `jinja`, `vue`, or the path of a Handlebars template file. A template is fed the `src`, `srcset`, `sizes`, `alt`, `width`, `height`, `format` and `type` of the fallback image, the `sources` of the other types and every one of the `variants` with its `url`, `width`, `height`, `bytes`, `format` and `descriptor`. The values of a template file are escaped for a quoted HTML attribute, and `{{js value}}` escapes one for a single quoted JavaScript string within an attribute.
+
+--alt-csv       A CSV of `filename,alt text` rows giving the **alt** text of images; the filename is the path relative to the input, the file name or the name without extension. The alt text of an image is read from a sidecar `my_image.alt` or `my_image.txt` first, then this mapping, then the EXIF ImageDescription, XMP `dc:description` or IPTC caption embedded in the image. An image with none gets `alt=""` and a warning.
+
+--loading       The **loading** attribute of the `<img>`, `lazy` or `eager`; by default there is none. The `<img>` always has the `width` and `height` of the legacy image, so the browser reserves its space before it loads.
+
+--decoding      The **decoding** attribute of the `<img>`, `async`, `sync` or `auto`; by default there is none.
+
+--metadata      The **metadata** of the original kept in each output: `strip` (the default) keeps none, so no GPS position or camera serial leaks; `copyright` keeps the EXIF Artist and Copyright; `all` keeps the EXIF and XMP. It is embedded in JPEG, PNG and WebP outputs, and is read from JPEG, PNG, WebP and TIFF originals. The outputs are upright, so any orientation kept is reset.
+
+--icc           What becomes of the **ICC** colour profile of the original: `srgb` (the default) converts the pixels of an Adobe RGB, Display P3 or other RGB image to sRGB, which browsers assume of an image without a profile; `preserve` leaves the pixels alone and embeds the profile in each JPEG, PNG and WebP output. Other types cannot carry a profile, so an image written only in those is converted to sRGB all the same, and a warning names any written without it alongside ones that keep it. The profile of a TIFF original is read as well.
+
+--crops         Art-directed **crops**, each `name=media` in semicolon;separated form, such as `"square=(max-width: 600px);landscape=(min-width: 1200px)"`. The name is `square`, `portrait` (4x5), `landscape` (16x9), `story` (9x16) or any ratio such as `3x2`. Each crop is saved at every size as `square-320w.webp` and so on, and offered in the `<picture>` by a `<source media="(max-width: 600px)">` ahead of the uncropped image. A crop is centred on the focal point of the image: the `x,y` in a sidecar `my_image.focus`, such as `0.5,0.3` or `50%,30%`, or the `[focus]` table of a `srcset.toml`, or else the busiest part of the image by its edges.
+
+-x  --density   **density** mode for images shown at a fixed CSS width, such as avatars and logos. Given the display width in pixels, each image is scaled to 1x, 2x and 3x that width, saved as `1x.jpg`, `2x.jpg` ... and described in the tag by density rather than width: `srcset="my_image/1x.jpg 1x,my_image/2x.jpg 2x"` with no `sizes` attribute. Replaces the sizes.
+
+--densities     The densities of `-x` in comma,separated,value form; defaults to `1,2,3`.
+
+-q --quality    Quality with a value in the range 1-100 where 100 is the best; default is `82`. Only for jpegs, webps and avifs.
+
+--lossless      Encode webps **losslessly**, ignoring `-q`; best for graphics and logos. A webp keeps any transparency of the original either way.
+
+--avif-speed    The **speed** of the avif encoder in the range 1-10, where 1 is the slowest and smallest; default is `4`. Avif is written, keeping any transparency, by a build with the `avif-encoder` feature, but an `.avif` original is picked up only by a build with the `avif-decoder` feature.
+
+--ssim          Encode each jpeg, webp or avif variant at the lowest quality whose **SSIM** (structural similarity) to the resized image is at least this, such as `0.98`, in place of `-q`. A detailed photo gets a higher quality than a flat graphic. The SSIM is of the luma, and the quality chosen is found by encoding at several; an avif, which cannot be decoded to compare, keeps `-q`. A byte budget still applies afterwards.
+
+--max-bytes     The most bytes a jpeg, webp or avif variant may take, such as `80k` or `1.5m`. The quality of each is searched down from `-q` for the highest that fits; one that does not fit even at quality 1 is written at 1 with a warning. The quality chosen is shown with `-v` and given in the manifest.
+
+--max-bytes-sizes The byte budget of particular sizes as `width=bytes` in semicolon;separated form, such as `320=20k;640=50k`. A size not listed uses `--max-bytes`.
+
+--filter        The resampling **filter** of every resize: `nearest` for pixel art, `triangle` for speed, `catmullrom`, `gaussian` or `lanczos3`, the default. A source four or more times the size of a variant is first shrunk by a fast box filter to twice its size, which cuts the time of large resizes; `nearest` never is.
+
+-u  --unsharpen Unsharpen with a sigma float and threshold int; default is `0.25,8`.
+
+--unsharpen-sizes The unsharpen sigma and threshold of particular sizes as `width=sigma,threshold` in semicolon;separated form, such as `320=0.5,4;480=0.4,6`. A size not listed uses `-u`.
+
+--sharpen-by-ratio Scale the sigma of `-u` with the downscale ratio of each variant, by half again for each halving (2.25 times at a quarter of the width), so that a thumbnail cut from a large photo is sharpened more than a variant near its full size.
+
+--nosharpen     Dont sharpen the variants at all.
+
+-m  --min       Set the **minimum** size of image that will be processed; otherwise an image will be skipped. Ignored for single files. Specifed in Kilobytes. The default is `100` (aka  a min of `102400` bytes). 
+
+-p --prefix     String prefix to the filenames within the <img/> tag, such as `/var/www/html/pics`.
+
+-j  --jobs      Whether to use parallel threaded **jobs** on image conversion. Files are processed in parallel, and the sizes of each file; the output keeps the sorted order of the files.
+
+--threads       The number of parallel **threads** used by `-j`; defaults to one per core.
+
+-n  --nest      Use a **nested** directory hierarchy on the output, otherwise it is flat. ignored for single file.
+
+-i  --incremental Skip any image whose outputs (`legacy`, each size and `srcset.txt`) already exist and are newer than the original; these are counted as up to date.
+
+-c  --cache     Keep a content-hash **cache** index, `.srcset-cache.json`, in the output directory. An image is skipped when the same content was already resized with the same options and its outputs are still present; unlike `-i` this survives a fresh checkout, so the output directory can be restored from a CI cache.
+
+-a  --hash      Embed a short content **hash** of each resized image in its filename, such as `320w.3fa9c1.webp` and `legacy.de34bc.jpg`. The tag and manifest reference the hashed names, so a changed image gets new URLs and the outputs can be served with `Cache-Control: immutable`.
+
+-z  --test      Run a test or **null** run. File paths are traversed but no images are generated and no new file path is created. The `<img>` markup will be generated to the console.
+
+--noconfig      Ignore any `srcset.toml` **configuration** file; see CONFIGURATION.
+
+-v   --verbose  Use **verbose** output.
+
+-e  --quiet     **quiet** the errors; functionaly the same as piping error to null, `2>/dev/null` 
+
+--manifest      Write a JSON **manifest** of the run to the provided file; each source image mapped to its variants (paths, widths, heights, byte sizes and formats) and tag, plus the run counts.
+
+--version      Display the **versio**.
+
+-h --help       Display the **help**.
+
+## USE
+
+`srcset` is built using Rust known for its speed plus it leverages modern multi-core architectures. Use the `-j` directive to turn on parallel jobs.
+
+`srcset` requires a file path, whether filename or file hierarcy. If a filename, that single file will resized. If a file hierarchy, the files within that directory will be resized. Specifying the option `r` it will walk the file hierarchy resizing any found images.
+
+The utility resizes each image using the same compression as the original image; however, specify the desination type using the `-t` directive. *srcset* permits the use of an image in TIFF format -- that is often the second step after Nikon, Canon and other 'raw' native formats -- from which `convert` can generate the final HTML-ready images. Or you can stick with the tried JPEG, PNG and GIF.
+
+The newly added Webp format is recommended since it offers both lossless and lossy compression in one convenient format. Google claims that its lossless images are 26% smaller that PNGs while its lossy images are 25-34% smaller than JPEGS at the same quality.
+
+ ##  FILE STRUCTURE 
+ 
+Due to the large number of resized images, they are organized into a file structure. The name of the directory matches the original filename. The name of each resized image contains the width of the image and placed into the directory from `320w` to `1440w`. The original file is copied, placed into the directory and renamed to `legacy`. Therefore, `srcset` will skip over any files named `legacy`, `320w`, `480w`,.... `1440w`, or with crops `square-320w` and so on for each crop given, to avoid duplicate work.
+
+For example, given an image named `my_image` the following directory will be constructed.
+
+```text
+srcset my_image.jpg
+
+- my_image/
+        legacy.jpg
+        320w.jpg
+        480w.jpg
+        640w.jpg
+        768w.jpg
+        960w.jpg
+        1024w.jpg
+        1280w.jpg
+        1440w.jpg
+```
+
+The resulting tag is:
+
+```text
+<img src="my_image/legacy.jpg" srcset="my_image/320w.jpg 320w, my_image/480w.jpg 480w, my_image/640w.jpg 640w, my_image/768w.jpg 768w, my_image/960w.jpg 960w, my_image/1024w.jpg 1024w, my_image/1280w.jpg 1280w, my_image/1440w.png 1440w" sizes="(min-width: 768px) 50vw, 100vw" alt="My image">
+```
+
+## CONFIGURATION
+
+A `srcset.toml` in the input root sets the sizes (or `density` and `densities`), the `sizes_attr`, the `template`, the `alt_csv`, the `loading` and `decoding` attributes, the `metadata` and `icc` policies, the `crops` and a `[focus]` table of focal points by file name, formats, quality, `lossless`, `avif_speed`, `ssim`, `max_bytes` (with a `[max_bytes_sizes]` table), filter, unsharpen (with `sharpen`, `sharpen_by_ratio` and an `[unsharpen_sizes]` table), prefix, nesting and minimum size; one in a subdirectory overrides its parents for the images under it, and options on the command line override every file. See the `config` module.
+
+## LIBRARY
+
+The same work is available to Rust programs. Build the options, then `run` a file or file hierarchy:
+
+```no_run
+let opts = srcset::Opts::builder("examples/simple")
+    .outpath("/var/www/images")
+    .extensions(["avif", "webp", "jpg"])
+    .recurse(true)
+    .build()?;
+
+let metrics = srcset::run(&opts, &mut |processed| println!("{}", processed.tag))?;
+println!("{} images resized", metrics.count);
+# Ok::<(), srcset::Error>(())
+```
+
+*/
+
+use std::path::Path;
+
+pub mod error;
+pub mod opts;
+pub mod img;
+pub mod walk;
+pub mod img_ext;
+pub mod manifest;
+pub mod cache;
+pub mod config;
+pub mod sizes_attr;
+pub mod template;
+pub mod alt;
+pub mod orientation;
+pub mod metadata;
+pub mod icc;
+pub mod crop;
+pub mod ssim;
+mod utils;
+
+pub use crate::error::{Error, Result};
+pub use crate::opts::{Opts, OptsBuilder, Metrics};
+pub use crate::img::{process_image, scale_and_save, create_tag, ProcessedImage, Variant};
+pub use crate::walk::{walk_path, digest_path, collect_paths, process_paths};
+pub use crate::manifest::Manifest;
+pub use crate::config::Config;
+pub use crate::template::{Template, TagData};
+pub use crate::alt::AltMap;
+pub use crate::metadata::{Policy, Metadata};
+pub use crate::icc::IccPolicy;
+pub use crate::crop::{Crop, FocalPoint};
+
+/// Resize the file or walk the file hierarchy named by `opts.inpath`, returning the counts.
+/// Every image processed is handed to `on_image` as it completes. Any cache is saved at the end.
+///
+/// A file hierarchy is walked with `walk_path`, where each image that fails is reported and skipped.
+/// A single file is handed to `process_image` and any error is returned.
+pub fn run(opts: &Opts, on_image: &mut dyn FnMut(&ProcessedImage)) -> Result<Metrics> {
+    let mut m = Metrics::default();
+    let inpath: &Path = &opts.inpath;
+
+    match inpath.is_dir() {
+        true => walk_path(inpath, opts, &mut m, on_image)?,
+        _ => {
+            let file_opts = match opts.is_config {
+                true => config::file_opts(inpath, opts)?,
+                false => opts.clone(),
+            };
+            on_image(&process_image(inpath, &file_opts, &mut m)?)
+        },
+    }
+
+    if let Some(cache) = &opts.cache {
+        if !opts.is_test {
+            cache.save()?;
+        }
+    }
+
+    Ok(m)
+}
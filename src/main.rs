@@ -1,142 +1,62 @@
+//! The srcset command-line utility; a thin wrapper that parses the arguments into
+//! `srcset::Opts` and hands them to the library. See the crate documentation for the options.
 
-/*!
-
-# NAME
-
-**srcset** -- generate multiple responsive images for web and mobile.
-
-## SUMMARY
-
-The srcset utility generates multiple (eight) scaled versions of an image at particular breakpoints
-those of 320,480,640,768,960,1024,1280,1440 pixels wide, the widths match common Mobile and widescreen viewports.
-
-It convert images from jpg, png, tiff formats; and outputs the needed <img> tag.
-
-## SYNOPSIS
-
-`./srset [-rjnvzh] [—t type] [-s sizes] [-o outpath] filename`
-
-`./srset [-rjnvzh] [—t type] [-s sizes] [-o outpath] file hierarchy`
-
-## DESCRIPTION
-
-A file path, whether filename or file hierarcy is required. Specify the path (file or file hierarchy) to generate images. The type of file path, whether file or file hierarchy is determined by srcset.
-
-The options are as follows:
-
--r  --recurse   **recurse** the provided directory. ignored for single file.
-
--o  --out       The **output** directory for the resized image. defaults to `/tmp/srcset/`; windows its `srcset`
-
--t  --type      The **type** of image conversion (png, jpg, webp, ... ); defaults to the same type as the original image found in the input path.
-
--s  --size      The **sizes** for responsive images in comma,separated,value form; defaults to `480, 640, 768, 960, 1024, 1366, 1600, 1920`.
-
--q --quality    Quality with a value in the range 1-100 where 100 is the best; default is `82`. Only for jpegs.
-
--u  --unsharpen Unsharpen with a sigma float and threshold int; default is `0.25,8`.
-
--m  --min       Set the **minimum** size of image that will be processed; otherwise an image will be skipped. Ignored for single files. Specifed in Kilobytes. The default is `100` (aka  a min of `102400` bytes). 
-
--p --prefix     String prefix to the filenames within the <img/> tag, such as `/var/www/html/pics`.
-
--j  --jobs      Whether to use parallel threaded **jobs** on image conversion.
-
--n  --nest      Use a **nested** directory hierarchy on the output, otherwise it is flat. ignored for single file.
-
--z  --test      Run a test or **null** run. File paths are traversed but no images are generated and no new file path is created. The `<img>` markup will be generated to the console.
-
--v   --verbose  Use **verbose** output.
-
--e  --quiet     **quiet** the errors; functionaly the same as piping error to null, `2>/dev/null` 
-
---version      Display the **versio**.
-
--h --help       Display the **help**.
-
-## USE
-
-`srcset` is built using Rust known for its speed plus it leverages modern multi-core architectures. Use the `-j` directive to turn on parallel jobs.
-
-`srcset` requires a file path, whether filename or file hierarcy. If a filename, that single file will resized. If a file hierarchy, the files within that directory will be resized. Specifying the option `r` it will walk the file hierarchy resizing any found images.
-
-The utility resizes each image using the same compression as the original image; however, specify the desination type using the `-t` directive. *srcset* permits the use of an image in TIFF format -- that is often the second step after Nikon, Canon and other 'raw' native formats -- from which `convert` can generate the final HTML-ready images. Or you can stick with the tried JPEG, PNG and GIF.
-
-The newly added Webp format is recommended since it offers both lossless and lossy compression in one convenient format. Google claims that its lossless images are 26% smaller that PNGs while its lossy images are 25-34% smaller than JPEGS at the same quality.
-
- ##  FILE STRUCTURE 
- 
-Due to the large number of resized images, they are organized into a file structure. The name of the directory matches the original filename. The name of each resized image contains the width of the image and placed into the directory from `320w` to `1440w`. The original file is copied, placed into the directory and renamed to `legacy`. Therefore, `srcset` will skip over any files named `legacy`, `320w`, `480w`,.... `1440w` to avoid duplicate work.
-
-For example, given an image named `my_image` the following directory will be constructed.
-
-```
-srcset my_image.jpg
-
-- my_image/
-        legacy.jpg
-        320w.jpg
-        480w.jpg
-        640w.jpg
-        768w.jpg
-        960w.jpg
-        1024w.jpg
-        1280w.jpg
-        1440w.jpg
-```
-
-The resulting tag is:
-
-```
-<img src="my_image/legacy.jpg" srcset="my_image/320w.jpg 320w, my_image/480w.jpg 480w, my_image/640w.jpg 640w, my_image/768w.jpg 768w, my_image/960w.jpg 960w, my_image/1024w.jpg 1024w, my_image/1280w.jpg 1280w, my_image/1440w.png 1440w" sizes="(min-width: 768px) 50vw, 100vw" alt="A file named my_image">
-```
-
-*/
-
-use std::path::{Path, PathBuf};
 use std::time::Instant;
 use yansi::Paint;
 
-mod utils;
-mod opts;
-mod img;
-mod walk;
-mod img_ext;
-
-use crate::opts::{Opts, Metrics};
-use crate::img::process_image;
-use crate::walk::walk_path;
+use srcset::opts::{parse_sizes, parse_densities, parse_extensions, parse_filter, parse_unsharpen, parse_unsharpen_sizes, parse_bytes, parse_max_bytes_sizes};
+use srcset::{Opts, Manifest, ProcessedImage, Variant, Template, AltMap};
+use srcset::crop::parse_crops;
 
 
 fn main() {
-   
+
    // The defaults!
-    let mut inpath_str = "".to_string();
+    let defaults = Opts::default();
 
-    let mut outpath_str =
-    if cfg!(target_family = "windows") {
-        "srcset".to_string()
-    } else {
-        "/tmp/srcset/".to_string()
-    };
+    let mut inpath_str = "".to_string();
+    let mut outpath_str = defaults.outpath.to_string_lossy().into_owned();
 
-    
-    
-    let mut is_file = false;
     let mut extension = "".to_string();
     let mut prefix = "".to_string();
 
-    let mut sizes = "320, 480, 640, 768, 960, 1024, 1366, 1600, 1920".to_string();
+    let mut sizes = "".to_string();
+    let mut density: Option<u32> = None;
+    let mut densities = "".to_string();
+    let mut sizes_attr = "".to_string();
+    let mut template = "".to_string();
+    let mut alt_csv = "".to_string();
+    let mut loading = "".to_string();
+    let mut decoding = "".to_string();
+    let mut metadata = "".to_string();
+    let mut icc = "".to_string();
+    let mut crops = "".to_string();
 
     let mut is_recurse = false;
     let mut is_jobs = false;
+    let mut threads = 0;
     let mut is_nested = false;
+    let mut is_incremental = false;
+    let mut is_cached = false;
+    let mut is_hashed = false;
     let mut is_test = false;
     let mut is_verbose = false;
     let mut is_quiet = false;
-    let mut min_kb = 100;
-    let mut quality = 82;
-    let mut unsharpen = "0.25,8".to_string();
+    let mut min_kb: Option<u64> = None;
+    let mut quality: Option<u8> = None;
+    let mut max_bytes = "".to_string();
+    let mut max_bytes_sizes = "".to_string();
+    let mut ssim: Option<f64> = None;
+    let mut is_lossless = false;
+    let mut avif_speed: Option<u8> = None;
+    let mut filter = "".to_string();
+    let mut unsharpen = "".to_string();
+    let mut unsharpen_sizes = "".to_string();
+    let mut is_sharpen = true;
+    let mut is_sharpen_by_ratio = false;
+    let mut is_config = true;
+
+    let mut manifest_str = "".to_string();
 
     let mut is_tagfile = true;
     let mut use_largest = true;
@@ -146,8 +66,8 @@ fn main() {
     {
         let mut args = argparse::ArgumentParser::new();
 
-        const DESCRIPTION_STRING: &'static str =
-        concat!("srcset command-line utility v", env!("CARGO_PKG_VERSION"));        
+        const DESCRIPTION_STRING: &str =
+        concat!("srcset command-line utility v", env!("CARGO_PKG_VERSION"));
 
         args.set_description(DESCRIPTION_STRING);
 
@@ -161,7 +81,7 @@ fn main() {
 
         args.refer(&mut extension)
                 .add_option(&["-t", "--type"], argparse::Store,
-                "Output filetype (jpg, png, etc)");
+                "Output filetypes in comma,separated form (webp,avif,jpg); the last is the <img> fallback");
 
         args.refer(&mut prefix)
                 .add_option(&["-p", "--prefix"], argparse::Store,
@@ -171,18 +91,74 @@ fn main() {
                 .add_option(&["-s", "--sizes"], argparse::Store,
                 "The sizes for responsive images: defaults to \"320, 480, 640, 768, 960, 1024, 1366, 1600, 1920\"");
 
+        args.refer(&mut sizes_attr)
+                .add_option(&["--sizes-attr"], argparse::Store,
+                "The sizes attribute of the tag, such as \"(min-width: 1024px) 50vw, 100vw\"; defaults to one picked by width");
+
+        args.refer(&mut template)
+                .add_option(&["--template"], argparse::Store,
+                "The markup template: html, jsx, markdown, liquid, jinja, vue or the path of a Handlebars file; default is html");
+
+        args.refer(&mut alt_csv)
+                .add_option(&["--alt-csv"], argparse::Store,
+                "A CSV of filename,alt text rows, used for images without an .alt or .txt sidecar file");
+
+        args.refer(&mut loading)
+                .add_option(&["--loading"], argparse::Store,
+                "The loading attribute of the <img>: lazy or eager; default is none");
+
+        args.refer(&mut decoding)
+                .add_option(&["--decoding"], argparse::Store,
+                "The decoding attribute of the <img>: async, sync or auto; default is none");
+
+        args.refer(&mut metadata)
+                .add_option(&["--metadata"], argparse::Store,
+                "The metadata of the original kept in the outputs: strip, copyright or all; default is strip");
+
+        args.refer(&mut icc)
+                .add_option(&["--icc"], argparse::Store,
+                "The colour profile of the original: srgb converts the pixels, preserve embeds the profile in the outputs; default is srgb");
+
+        args.refer(&mut crops)
+                .add_option(&["--crops"], argparse::Store,
+                "Art-directed crops as name=media in semicolon;separated form, such as \"square=(max-width: 600px)\"; the name is square, portrait, landscape, story or a ratio such as 16x9");
+
+        args.refer(&mut density)
+                .add_option(&["-x", "--density"], argparse::StoreOption,
+                "Density mode: the CSS display width in pixels, scaled to 1x, 2x and 3x in place of the sizes");
+
+        args.refer(&mut densities)
+                .add_option(&["--densities"], argparse::Store,
+                "The densities of density mode in comma,separated form: defaults to \"1,2,3\"");
+
         args.refer(&mut min_kb)
-                .add_option(&["-m", "--min"], argparse::Store,
+                .add_option(&["-m", "--min"], argparse::StoreOption,
                 "Minimum size of image to process in kb, otherwise skip");
 
         args.refer(&mut is_jobs)
                 .add_option(&["-j", "--job"], argparse::StoreTrue,
                 "Use parallel jobs");
 
+        args.refer(&mut threads)
+                .add_option(&["--threads"], argparse::Store,
+                "Number of parallel jobs with -j; defaults to one per core");
+
         args.refer(&mut is_nested)
                 .add_option(&["-n", "--nested"], argparse::StoreTrue,
                 "Images are saved in a nested hierarchy");
 
+        args.refer(&mut is_incremental)
+                .add_option(&["-i", "--incremental"], argparse::StoreTrue,
+                "Skip images whose outputs are newer than the source");
+
+        args.refer(&mut is_cached)
+                .add_option(&["-c", "--cache"], argparse::StoreTrue,
+                "Keep a content-hash cache in the output directory and skip work already done");
+
+        args.refer(&mut is_hashed)
+                .add_option(&["-a", "--hash"], argparse::StoreTrue,
+                "Embed a content hash in each output filename (320w.3fa9c1.webp)");
+
         args.refer(&mut is_test)
                 .add_option(&["-z", "--test"], argparse::StoreTrue,
                 "Test run. Images are found but not created");
@@ -200,104 +176,192 @@ fn main() {
                 "Path (Filename or directory) of image");
 
         args.refer(&mut quality)
-                .add_option(&["-q", "--quality"], argparse::Store,
+                .add_option(&["-q", "--quality"], argparse::StoreOption,
                 "Quality with a value in the range 1-100 where 100 is the best; default is 82");
 
+        args.refer(&mut max_bytes)
+                .add_option(&["--max-bytes"], argparse::Store,
+                "The most bytes a JPEG, WebP or AVIF variant may take, such as 80k; the quality is lowered until it fits");
+
+        args.refer(&mut max_bytes_sizes)
+                .add_option(&["--max-bytes-sizes"], argparse::Store,
+                "The byte budget of particular sizes as width=bytes in semicolon;separated form, such as \"320=20k;640=50k\"");
+
+        args.refer(&mut is_lossless)
+                .add_option(&["--lossless"], argparse::StoreTrue,
+                "Encode WebP losslessly, as for graphics and logos, ignoring -q");
+
+        args.refer(&mut avif_speed)
+                .add_option(&["--avif-speed"], argparse::StoreOption,
+                "The AVIF encoder speed in the range 1-10 where 1 is the slowest and smallest; default is 4");
+
+        args.refer(&mut ssim)
+                .add_option(&["--ssim"], argparse::StoreOption,
+                "Encode each JPEG, WebP or AVIF at the lowest quality with at least this SSIM to the resized image, such as 0.98; in place of -q");
+
+        args.refer(&mut filter)
+                .add_option(&["--filter"], argparse::Store,
+                "The resampling filter: nearest, triangle, catmullrom, gaussian or lanczos3; default is lanczos3");
+
         args.refer(&mut unsharpen)
                 .add_option(&["-u", "--unsharpen"], argparse::Store,
                 "Unsharpen with a sigma float and threshold int; default is 0.25,8");
 
+        args.refer(&mut unsharpen_sizes)
+                .add_option(&["--unsharpen-sizes"], argparse::Store,
+                "Unsharpen particular sizes as width=sigma,threshold in semicolon;separated form, such as \"320=0.5,4;480=0.4,6\"");
+
+        args.refer(&mut is_sharpen_by_ratio)
+                .add_option(&["--sharpen-by-ratio"], argparse::StoreTrue,
+                "Scale the unsharpen sigma with the downscale ratio, so that smaller variants are sharpened more");
+
+        args.refer(&mut is_sharpen)
+                .add_option(&["--nosharpen"], argparse::StoreFalse,
+                "Dont sharpen the variants");
+
         args.refer(&mut is_tagfile)
-                .add_option(&["-d", "--notag"], argparse::StoreTrue,
+                .add_option(&["-d", "--notag"], argparse::StoreFalse,
                 "Dont create a tag file");
 
+        args.refer(&mut manifest_str)
+                .add_option(&["--manifest"], argparse::Store,
+                "Write a JSON manifest of every image, its variants and tag to this file");
+
         args.refer(&mut use_largest)
                 .add_option(&["-l", "--largest"], argparse::StoreTrue,
                 "Scale to the largest size");
 
+        args.refer(&mut is_config)
+                .add_option(&["--noconfig"], argparse::StoreFalse,
+                "Ignore any srcset.toml in the input root and its subdirectories");
+
         args.refer(&mut is_version)
                 .add_option(&["--version"], argparse::StoreTrue,
                 "Print version and exit");
 
         args.parse_args_or_exit();
     }
-    
+
 
     if is_version {
         println!("srcset {}", env!("CARGO_PKG_VERSION"));
-        std::process::exit(1);        
+        std::process::exit(1);
     }
 
     // Output must end in `/` so simply append one.
-    if !outpath_str.ends_with("/") {  outpath_str.push_str("/"); }
+    if !outpath_str.ends_with('/') {  outpath_str.push('/'); }
+
+    // Only the options given are set on the builder, so that a srcset.toml can supply the rest
+    let opts = (|| {
+        let mut builder = Opts::builder(&inpath_str)
+                .outpath(&outpath_str)
+                .recurse(is_recurse)
+                .jobs(is_jobs)
+                .threads(threads)
+                .incremental(is_incremental)
+                .cache(is_cached)
+                .hashed(is_hashed)
+                .test(is_test)
+                .verbose(is_verbose)
+                .quiet(is_quiet)
+                .tagfile(is_tagfile)
+                .largest(use_largest)
+                .config(is_config);
+
+        if !extension.trim().is_empty() { builder = builder.extensions(parse_extensions(&extension)); }
+        if !prefix.is_empty() { builder = builder.prefix(prefix); }
+        if !sizes.trim().is_empty() { builder = builder.sizes(parse_sizes(&sizes)?); }
+        if !sizes_attr.trim().is_empty() { builder = builder.sizes_attr(sizes_attr.trim()); }
+        if !template.trim().is_empty() { builder = builder.template(Template::from_arg(&template)?); }
+        if !alt_csv.trim().is_empty() { builder = builder.alts(AltMap::load(alt_csv.trim())?); }
+        if !loading.trim().is_empty() { builder = builder.loading(loading.trim()); }
+        if !decoding.trim().is_empty() { builder = builder.decoding(decoding.trim()); }
+        if !metadata.trim().is_empty() { builder = builder.metadata(metadata.parse()?); }
+        if !icc.trim().is_empty() { builder = builder.icc(icc.parse()?); }
+        if !crops.trim().is_empty() { builder = builder.crops(parse_crops(&crops)?); }
+        if let Some(density) = density { builder = builder.density(density); }
+        if !densities.trim().is_empty() { builder = builder.densities(parse_densities(&densities)?); }
+        if let Some(min_kb) = min_kb { builder = builder.min_kb(min_kb); }
+        if let Some(quality) = quality { builder = builder.quality(quality); }
+        if is_lossless { builder = builder.lossless(true); }
+        if let Some(speed) = avif_speed { builder = builder.avif_speed(speed); }
+        if let Some(ssim) = ssim { builder = builder.ssim(ssim); }
+        if !max_bytes.trim().is_empty() { builder = builder.max_bytes(parse_bytes(&max_bytes)?); }
+        if !max_bytes_sizes.trim().is_empty() { builder = builder.max_bytes_sizes(parse_max_bytes_sizes(&max_bytes_sizes)?); }
+        if !filter.trim().is_empty() { builder = builder.filter(parse_filter(&filter)?); }
+        if !unsharpen.trim().is_empty() {
+            let (sigma, thresh) = parse_unsharpen(&unsharpen)?;
+            builder = builder.unsharpen(sigma, thresh);
+        }
+        if !unsharpen_sizes.trim().is_empty() { builder = builder.unsharpen_sizes(parse_unsharpen_sizes(&unsharpen_sizes)?); }
+        if is_sharpen_by_ratio { builder = builder.sharpen_by_ratio(true); }
+        if !is_sharpen { builder = builder.sharpen(false); }
+        if is_nested { builder = builder.nested(true); }
+
+        builder.build()
+    })();
+
+    let opts = match opts {
+        Ok(opts) => opts,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Seems like a whitespace creeps in?
-    extension = extension.trim().to_string();
+    let mut manifest = Manifest::new();
 
-    if inpath_str == "" {
-        println!("File or directory argument is required.");
-        std::process::exit(1);
-    }
+    let start = Instant::now();
+    let m = match srcset::run(&opts, &mut |processed| { print_image(processed, &opts); manifest.add(processed); }) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{} {:?}, {}", Paint::red("ERROR: Processing error: "), opts.inpath, e);
+            std::process::exit(1);
+        },
+    };
+    let duration = start.elapsed();
 
-    let inpath = PathBuf::from(&inpath_str);
-    
-    if inpath.is_file() {
-        is_nested = false;
-        is_recurse = false;
-        is_file = true;
-    }
-    
-    let outpath = PathBuf::from(&outpath_str);
-    if outpath.is_file() {
-        println!("Selected outpath cannot be a file.");
-        std::process::exit(1);
+    if !manifest_str.is_empty() {
+        manifest.metrics = m.clone();
+        if let Err(e) = manifest.write(&manifest_str) {
+            eprintln!("{} {:?}, {:?}", Paint::red("WARNING: Manifest error: "), manifest_str, e);
+        }
     }
 
-    // Convert size string by comma
-    let split = sizes.split(",");
-    let mut vec: Vec<u32> = vec![];
-    for s in split {
-        let x = s.trim_matches(' ');
-        vec.push(x.parse::<u32>().unwrap() );
-    }
+    println!("Count: {}, Resized: {}, Traversed: {}, Skipped {}, Up to date {} ", Paint::green(m.count), Paint::yellow(m.resized), Paint::blue(m.traversed), Paint::red(m.skipped), Paint::cyan(m.up_to_date));
+    println!("{:?}", Paint::green(duration));
+}
 
-    let sigmathresh: Vec<&str> = unsharpen.split(",").collect();
-    let sigma = sigmathresh[0].parse::<f32>().unwrap();
-    let thresh = sigmathresh[1].parse::<i32>().unwrap();
 
+/// The console consumer of each processed image: the path, the sizes and the tag.
+fn print_image(processed: &ProcessedImage, opts: &Opts) {
+    let path = processed.source.as_path();
+    if opts.is_file {
+        println!("{:?}", Paint::green(path));
+    } else {
+        println!("{:?}", Paint::green(path.strip_prefix(opts.inpath.as_path()).unwrap_or(path)));
+    }
 
-    let opts = Opts{inpath: inpath, outpath: outpath, 
-                    is_file: is_file, extension: extension, 
-                    prefix: prefix, min_size: min_kb * 1024, 
-                    is_recurse: is_recurse, is_jobs: is_jobs, is_nested: is_nested, 
-                    is_test: is_test, is_dir: true, is_verbose: is_verbose, 
-                    is_quiet: is_quiet, sizes: vec, quality: quality,
-                    sigma: sigma, thresh: thresh, is_tagfile: is_tagfile,
-                    use_largest: use_largest};
-    
-    let mut m = Metrics{count: 0, resized: 0, traversed: 0, skipped: 0 };
+    println!("\n{:?}\n\n", Paint::blue(processed.sizes()) );
 
-    let inpath = Path::new(&inpath_str);
+    if opts.is_verbose {
+        println!("{:?} Width={}: Height={}", path, Paint::red(processed.width), processed.height);
+        print_variant(&processed.legacy, opts);
+        for v in &processed.variants {
+            print_variant(v, opts);
+        }
+        if let Some(f) = &processed.tagfile { println!("{:?}", f);}
+    }
 
-    let start = Instant::now();
-    let _result =
-        match inpath.is_dir()
-        {
-            true => walk_path(&inpath, &opts, &mut m),
-            _ => {
-                match process_image(&inpath, &opts, &mut m) {
-                    Err(e) => { if !opts.is_quiet{eprintln!("{} {:?}, {:?}", Paint::red("WARNING: Processing error: "), inpath, e)}},
-                    _ => (),           
-                }
-                Ok(())
-                },                              
-           
-//            process_image(&inpath, &opts, &mut m),
-            
-        };
-    let duration = start.elapsed();
-    
-    println!("Count: {}, Resized: {}, Traversed: {}, Skipped {} ", Paint::green(m.count), Paint::yellow(m.resized), Paint::blue(m.traversed), Paint::red(m.skipped));
-    println!("{:?}", Paint::green(duration));
+    println!("\n{}\n\n", Paint::blue(&processed.tag) );
 }
 
+fn print_variant(v: &Variant, opts: &Opts) {
+    match opts.is_test {
+        true => println!(">> {:?}", v.path),
+        false => match v.quality {
+            Some(quality) => println!("{:?} Width={}: Height={}; Size={}; Format={:?}; Quality={}", v.path, Paint::red(v.width), v.height, Paint::red(human_bytes::human_bytes(v.bytes as f64)), v.format, quality),
+            None => println!("{:?} Width={}: Height={}; Size={}; Format={:?}", v.path, Paint::red(v.width), v.height, Paint::red(human_bytes::human_bytes(v.bytes as f64)), v.format),
+        },
+    }
+}
@@ -0,0 +1,47 @@
+//! A JSON manifest of a whole run; each source image mapped to its variants and tag,
+//! plus the run-level `Metrics`. Meant for build pipelines rather than scraping `srcset.txt`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::ImageFormat;
+use serde::{Serialize, Serializer};
+
+use crate::error::Result;
+use crate::img::ProcessedImage;
+use crate::opts::Metrics;
+use crate::utils::mk_dir;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Manifest {
+    pub metrics: Metrics,
+    /// Keyed by the source path, so the document is sorted and stable between runs.
+    pub images: BTreeMap<String, ProcessedImage>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest::default()
+    }
+
+    /// Add, or replace, the entry for a processed image.
+    pub fn add(&mut self, processed: &ProcessedImage) {
+        self.images.insert(processed.source.to_string_lossy().into_owned(), processed.clone());
+    }
+
+    /// Write the manifest as pretty printed JSON, creating the parent directory if needed.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        mk_dir(path);
+        let fout = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(fout, self)?;
+        Ok(())
+    }
+}
+
+/// Serialize an `ImageFormat` by its lowercase name, such as `jpeg` or `webp`.
+pub(crate) fn serialize_format<S: Serializer>(format: &ImageFormat, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("{:?}", format).to_lowercase())
+}
@@ -0,0 +1,232 @@
+//! The metadata carried from a source image into its variants, by policy:
+//!
+//! - `strip` drops everything, the default so that GPS positions and the like never leak;
+//! - `copyright` keeps the EXIF Artist and Copyright;
+//! - `all` keeps the EXIF and XMP.
+//!
+//! The ICC profile is not metadata in this sense and has its own policy in `icc`. The variants are
+//! written upright, so any orientation kept is reset to 1. The metadata of a TIFF source is read
+//! from its first IFD, less the tags that lay out its pixels. The metadata is embedded by the
+//! JPEG, PNG and WebP writers in `img_ext`; the other types carry none.
+
+use std::io::Cursor;
+use std::path::Path;
+use std::str::FromStr;
+
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use img_parts::webp::CHUNK_EXIF;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// The EXIF held in a JPEG APP1 or WebP EXIF chunk may start with this.
+pub(crate) const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// What metadata of the source the variants keep.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Policy {
+    #[default]
+    Strip,
+    Copyright,
+    All,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Policy> {
+        match s.trim().to_lowercase().as_str() {
+            "strip" | "none" => Ok(Policy::Strip),
+            "copyright" => Ok(Policy::Copyright),
+            "all" | "keep" => Ok(Policy::All),
+            _ => Err(Error::InvalidOption(format!("metadata {:?} is not strip, copyright or all", s))),
+        }
+    }
+}
+
+/// The metadata to embed in each variant.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The TIFF structure of the EXIF, without any `Exif\0\0` prefix.
+    pub exif: Option<Vec<u8>>,
+    /// The XMP packet.
+    pub xmp: Option<Vec<u8>>,
+    /// The ICC profile, set by the `icc` policy rather than this one.
+    pub icc: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Read the metadata of the source that the policy keeps, without any ICC profile. Metadata
+    /// that cannot be read is left out rather than failing the image.
+    pub fn read(path: &Path, policy: Policy) -> Metadata {
+        if policy == Policy::Strip {
+            return Metadata::default();
+        }
+        let Ok(bytes) = std::fs::read(path) else { return Metadata::default() };
+
+        let xmp = xmp_packet(&bytes).map(<[u8]>::to_vec);
+        let bytes = Bytes::from(bytes);
+        let exif = match DynImage::from_bytes(bytes.clone()) {
+            // The EXIF chunk of a WebP is usually bare, which img-parts does not read
+            Ok(Some(DynImage::WebP(webp))) => webp.chunk_by_id(CHUNK_EXIF).and_then(|c| c.content().data())
+                .map(|d| d.strip_prefix(EXIF_PREFIX).unwrap_or(d).to_vec()),
+            Ok(Some(img)) => img.exif().map(|b| b.to_vec()),
+            _ => container_exif(&bytes),
+        };
+
+        match policy {
+            Policy::Strip => Metadata::default(),
+            Policy::Copyright => Metadata { exif: exif.and_then(copyright_exif), xmp: None, icc: None },
+            Policy::All => Metadata {
+                exif: exif.map(|mut e| { reset_orientation(&mut e); e }),
+                xmp: xmp.map(|x| reset_xmp_orientation(&x)),
+                icc: None,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc.is_none()
+    }
+}
+
+/// The XMP packet within a file, from `<x:xmpmeta` to `</x:xmpmeta>`.
+pub(crate) fn xmp_packet(bytes: &[u8]) -> Option<&[u8]> {
+    const END: &[u8] = b"</x:xmpmeta>";
+    let start = find(bytes, b"<x:xmpmeta")?;
+    let len = find(&bytes[start..], END)? + END.len();
+    Some(&bytes[start..start + len])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The tags of the first IFD of a TIFF that describe the picture rather than lay out its pixels.
+const DESCRIPTIVE: &[exif::Tag] = &[
+    exif::Tag::ImageDescription, exif::Tag::Make, exif::Tag::Model, exif::Tag::Orientation,
+    exif::Tag::XResolution, exif::Tag::YResolution, exif::Tag::ResolutionUnit, exif::Tag::Software,
+    exif::Tag::DateTime, exif::Tag::Artist, exif::Tag::Copyright,
+];
+
+/// The EXIF of a type img-parts does not read, chiefly a TIFF, whose first IFD is both the EXIF
+/// and the layout of the image; a new EXIF is written of the descriptive tags alone.
+fn container_exif(bytes: &[u8]) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    let fields: Vec<&exif::Field> = exif.fields()
+        .filter(|f| f.ifd_num == exif::In::PRIMARY)
+        .filter(|f| f.tag.context() != exif::Context::Tiff || DESCRIPTIVE.contains(&f.tag))
+        .collect();
+    write_exif(&fields, exif.little_endian())
+}
+
+/// A new EXIF of only the Artist and Copyright, if there are either.
+fn copyright_exif(exif: Vec<u8>) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_raw(exif).ok()?;
+    let fields: Vec<&exif::Field> = exif.fields()
+        .filter(|f| f.ifd_num == exif::In::PRIMARY)
+        .filter(|f| f.tag == exif::Tag::Artist || f.tag == exif::Tag::Copyright)
+        .collect();
+    write_exif(&fields, exif.little_endian())
+}
+
+/// The TIFF structure of the fields, if there are any.
+fn write_exif(fields: &[&exif::Field], little_endian: bool) -> Option<Vec<u8>> {
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, little_endian).ok()?;
+    Some(out.into_inner())
+}
+
+/// Set the Orientation in the first IFD of a TIFF structure to 1, in place.
+fn reset_orientation(tiff: &mut [u8]) {
+    let little = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let u16_at = |t: &[u8], at: usize| t.get(at..at + 2).map(|b| if little { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) });
+    let u32_at = |t: &[u8], at: usize| t.get(at..at + 4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if little { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    });
+
+    let Some(ifd) = u32_at(tiff, 4).map(|o| o as usize) else { return };
+    let Some(count) = u16_at(tiff, ifd) else { return };
+    for i in 0..count as usize {
+        let entry = ifd + 2 + i * 12;
+        // The tag, a SHORT type and the value held in the entry itself
+        if u16_at(tiff, entry) == Some(0x0112) && u16_at(tiff, entry + 2) == Some(3) {
+            let one = if little { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() };
+            if let Some(value) = tiff.get_mut(entry + 8..entry + 10) {
+                value.copy_from_slice(&one);
+            }
+        }
+    }
+}
+
+/// Set any tiff:Orientation of an XMP packet to 1.
+fn reset_xmp_orientation(xmp: &[u8]) -> Vec<u8> {
+    lazy_static::lazy_static! {
+        static ref ATTR: regex::bytes::Regex = regex::bytes::Regex::new(r#"tiff:Orientation="\d""#).unwrap();
+        static ref ELEMENT: regex::bytes::Regex = regex::bytes::Regex::new(r"<tiff:Orientation>\d</tiff:Orientation>").unwrap();
+    }
+    let xmp = ATTR.replace_all(xmp, &b"tiff:Orientation=\"1\""[..]);
+    ELEMENT.replace_all(&xmp, &b"<tiff:Orientation>1</tiff:Orientation>"[..]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exif_of(fields: &[exif::Field], little_endian: bool) -> Vec<u8> {
+        write_exif(&fields.iter().collect::<Vec<_>>(), little_endian).unwrap()
+    }
+
+    fn orientation(exif: Vec<u8>) -> Option<u32> {
+        let exif = exif::Reader::new().read_raw(exif).unwrap();
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).and_then(|f| f.value.get_uint(0))
+    }
+
+    #[test]
+    fn resets_the_orientation_in_either_byte_order() {
+        let fields = [
+            exif::Field { tag: exif::Tag::Make, ifd_num: exif::In::PRIMARY, value: exif::Value::Ascii(vec![b"ACME".to_vec()]) },
+            exif::Field { tag: exif::Tag::Orientation, ifd_num: exif::In::PRIMARY, value: exif::Value::Short(vec![8]) },
+        ];
+        for little_endian in [true, false] {
+            let mut exif = exif_of(&fields, little_endian);
+            assert_eq!(orientation(exif.clone()), Some(8));
+            reset_orientation(&mut exif);
+            assert_eq!(orientation(exif), Some(1));
+        }
+        // Not a TIFF structure at all
+        reset_orientation(&mut [0u8; 3]);
+    }
+
+    #[test]
+    fn resets_the_xmp_orientation_as_attribute_or_element() {
+        let xmp = reset_xmp_orientation(br#"<a tiff:Orientation="6"/><tiff:Orientation>8</tiff:Orientation>"#);
+        assert_eq!(xmp, br#"<a tiff:Orientation="1"/><tiff:Orientation>1</tiff:Orientation>"#);
+    }
+
+    #[test]
+    fn keeps_only_the_artist_and_copyright() {
+        let ascii = |s: &str| exif::Value::Ascii(vec![s.as_bytes().to_vec()]);
+        let fields = [
+            exif::Field { tag: exif::Tag::Make, ifd_num: exif::In::PRIMARY, value: ascii("ACME") },
+            exif::Field { tag: exif::Tag::Copyright, ifd_num: exif::In::PRIMARY, value: ascii("(c) Jane Doe") },
+            exif::Field { tag: exif::Tag::GPSLatitudeRef, ifd_num: exif::In::PRIMARY, value: ascii("N") },
+        ];
+        let kept = exif::Reader::new().read_raw(copyright_exif(exif_of(&fields, true)).unwrap()).unwrap();
+        assert_eq!(kept.fields().map(|f| f.tag).collect::<Vec<_>>(), [exif::Tag::Copyright]);
+        assert!(copyright_exif(exif_of(&fields[..1], true)).is_none());
+    }
+}
@@ -1,39 +1,683 @@
-
 //! The options passed in many functions.
 
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use image::imageops::FilterType;
+use serde::Serialize;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::template::Template;
+use crate::alt::AltMap;
+use crate::metadata::Policy;
+use crate::icc::IccPolicy;
+use crate::crop::{Crop, FocalPoint};
+use crate::img_ext::{Encoding, DEFAULT_AVIF_SPEED};
+
+/// The default breakpoints, in pixels wide.
+pub const DEFAULT_SIZES: [u32; 9] = [320, 480, 640, 768, 960, 1024, 1366, 1600, 1920];
 
-#[derive(Debug)]
+/// The default pixel densities of density mode.
+pub const DEFAULT_DENSITIES: [u32; 3] = [1, 2, 3];
+
+#[derive(Debug, Clone)]
 pub struct Opts {
     pub inpath:  PathBuf,
     pub outpath: PathBuf,
     pub prefix: String,
     pub is_file: bool,
-    pub extension: String,
+    /// The output types; the last is the fallback <img>. Empty keeps the type of the original.
+    pub extensions: Vec<String>,
     pub is_recurse: bool,
     pub is_test: bool,
     pub is_jobs: bool,
+    /// Workers processing files in parallel with `is_jobs`; zero uses one per core.
+    pub threads: usize,
     pub is_nested: bool,
-    pub is_dir: bool,
+    /// Skip images whose outputs are all newer than the source.
+    pub is_incremental: bool,
+    /// Embed a short content hash in each output filename, such as `320w.3fa9c1.webp`.
+    pub is_hashed: bool,
+    /// The content-hash cache loaded from the outpath, if enabled.
+    pub cache: Option<Arc<Cache>>,
     pub min_size: u64,
     pub is_verbose: bool,
     pub is_quiet: bool,
     pub sizes: Vec<u32>,
+    /// The CSS display width of density mode; each of `densities` is scaled from it and described
+    /// as `2x` rather than by width, in place of `sizes`.
+    pub density: Option<u32>,
+    pub densities: Vec<u32>,
+    /// The `sizes` attribute of the tag; when `None` it is picked by the width of the legacy image.
+    pub sizes_attr: Option<String>,
+    /// The template of the markup written to `srcset.txt`.
+    pub template: Template,
+    /// Alt text by file, consulted after any sidecar file.
+    pub alts: Option<Arc<AltMap>>,
+    /// The `loading` attribute of the <img>, `lazy` or `eager`.
+    pub loading: Option<String>,
+    /// The `decoding` attribute of the <img>, `async`, `sync` or `auto`.
+    pub decoding: Option<String>,
+    /// The metadata of the source kept in each output.
+    pub metadata: Policy,
+    /// Convert the pixels to sRGB, or keep the colour profile of the source.
+    pub icc: IccPolicy,
+    /// The art-directed crops, each saved as variants of its own and offered by media condition.
+    pub crops: Vec<Crop>,
+    /// The focal points of the crops by file name, from the `[focus]` tables.
+    pub focus: BTreeMap<String, FocalPoint>,
     pub quality: u8,
+    /// Encode WebP losslessly, for graphics and logos, rather than at `quality`.
+    pub is_lossless: bool,
+    /// The AVIF encoder speed in the range 1-10; slower is smaller at the same quality.
+    pub avif_speed: u8,
+    /// The most bytes a variant may take; the quality of a JPEG, WebP or AVIF is lowered from
+    /// `quality` until it fits.
+    pub max_bytes: Option<u64>,
+    /// The byte budget of particular sizes, in place of `max_bytes`.
+    pub max_bytes_sizes: BTreeMap<u32, u64>,
+    /// The least SSIM of a variant to its resized image; the quality of each JPEG, WebP or AVIF is
+    /// then the lowest that reaches it, in place of `quality`.
+    pub ssim: Option<f64>,
+    /// The resampling filter of every resize.
+    pub filter: FilterType,
     pub sigma: f32,
     pub thresh: i32,
+    /// Sharpen each variant with the unsharp mask; on by default.
+    pub is_sharpen: bool,
+    /// Scale the sigma with the downscale ratio, as a thumbnail loses more detail than a
+    /// variant near the size of the source.
+    pub is_sharpen_by_ratio: bool,
+    /// The sigma and threshold of particular sizes, which neither the default nor the ratio affect.
+    pub unsharpen_sizes: BTreeMap<u32, (f32, i32)>,
     pub is_tagfile: bool,
-    pub use_largest: bool
+    pub use_largest: bool,
+    /// Read the `srcset.toml` in the input root and its subdirectories.
+    pub is_config: bool,
+    /// The settings given explicitly, which no `srcset.toml` overrides.
+    pub overrides: Config,
+
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        let outpath = if cfg!(target_family = "windows") { "srcset" } else { "/tmp/srcset/" };
+
+        Opts {
+            inpath: PathBuf::new(),
+            outpath: PathBuf::from(outpath),
+            prefix: String::new(),
+            is_file: false,
+            extensions: vec![],
+            is_recurse: false,
+            is_test: false,
+            is_jobs: false,
+            threads: 0,
+            is_nested: false,
+            is_incremental: false,
+            is_hashed: false,
+            cache: None,
+            min_size: 100 * 1024,
+            is_verbose: false,
+            is_quiet: false,
+            sizes: DEFAULT_SIZES.to_vec(),
+            density: None,
+            densities: DEFAULT_DENSITIES.to_vec(),
+            sizes_attr: None,
+            template: Template::default(),
+            alts: None,
+            loading: None,
+            decoding: None,
+            metadata: Policy::Strip,
+            icc: IccPolicy::Srgb,
+            crops: vec![],
+            focus: BTreeMap::new(),
+            quality: 82,
+            is_lossless: false,
+            avif_speed: DEFAULT_AVIF_SPEED,
+            max_bytes: None,
+            max_bytes_sizes: BTreeMap::new(),
+            ssim: None,
+            filter: FilterType::Lanczos3,
+            sigma: 0.25,
+            thresh: 8,
+            is_sharpen: true,
+            is_sharpen_by_ratio: false,
+            unsharpen_sizes: BTreeMap::new(),
+            is_tagfile: true,
+            use_largest: true,
+            is_config: true,
+            overrides: Config::default(),
+        }
+    }
+}
+
+impl Opts {
+    /// Start building options for the provided input path (file or file hierarchy).
+    pub fn builder<P: Into<PathBuf>>(inpath: P) -> OptsBuilder {
+        OptsBuilder { opts: Opts { inpath: inpath.into(), ..Opts::default() }, is_cached: false }
+    }
+
+    /// The encoding of each variant before any search of its quality.
+    pub fn encoding(&self) -> Encoding {
+        Encoding { quality: self.quality, lossless: self.is_lossless, avif_speed: self.avif_speed }
+    }
 
+    /// Check the options that can come from a `srcset.toml` as well as the builder, then
+    /// sort the sizes and densities and drop duplicate sizes, densities and types.
+    pub fn validate(&mut self) -> Result<()> {
+        if self.sizes.is_empty() {
+            return Err(Error::InvalidOption("at least one size is required".to_string()));
+        }
+        if let Some(size) = self.sizes.iter().find(|s| **s == 0) {
+            return Err(Error::InvalidOption(format!("size {} must be greater than zero", size)));
+        }
+        if self.density == Some(0) {
+            return Err(Error::InvalidOption("density width must be greater than zero".to_string()));
+        }
+        if self.densities.is_empty() || self.densities.contains(&0) {
+            return Err(Error::InvalidOption("densities must be one or more of 1, 2, 3 ...".to_string()));
+        }
+        if !(1..=100).contains(&self.quality) {
+            return Err(Error::InvalidOption(format!("quality {} is not in the range 1-100", self.quality)));
+        }
+
+        if !(1..=10).contains(&self.avif_speed) {
+            return Err(Error::InvalidOption(format!("avif speed {} is not in the range 1-10", self.avif_speed)));
+        }
+        if self.max_bytes == Some(0) || self.max_bytes_sizes.values().any(|b| *b == 0) {
+            return Err(Error::InvalidOption("max bytes must be greater than zero".to_string()));
+        }
+        if let Some(ssim) = self.ssim.filter(|s| !(*s > 0.0 && *s < 1.0)) {
+            return Err(Error::InvalidOption(format!("ssim {} is not between 0 and 1, such as 0.98", ssim)));
+        }
+
+        if let Some(loading) = self.loading.as_deref().filter(|l| !["lazy", "eager"].contains(l)) {
+            return Err(Error::InvalidOption(format!("loading {:?} is not lazy or eager", loading)));
+        }
+        if let Some(decoding) = self.decoding.as_deref().filter(|d| !["async", "sync", "auto"].contains(d)) {
+            return Err(Error::InvalidOption(format!("decoding {:?} is not async, sync or auto", decoding)));
+        }
+        if let Some((i, crop)) = self.crops.iter().enumerate().find(|(i, c)| self.crops[..*i].iter().any(|o| o.name == c.name)) {
+            return Err(Error::InvalidOption(format!("crop {:?} is given more than once, as crop {}", crop.name, i + 1)));
+        }
+        if let Some(sizes_attr) = &self.sizes_attr {
+            crate::sizes_attr::validate(sizes_attr)?;
+        }
+        for crop in &self.crops {
+            crate::sizes_attr::validate_media(&crop.media)?;
+        }
+
+        self.sizes.sort_unstable();
+        self.sizes.dedup();
+        self.densities.sort_unstable();
+        self.densities.dedup();
+
+        for ext in &self.extensions {
+            if image::ImageFormat::from_extension(ext).is_none() {
+                return Err(Error::InvalidOption(format!("unknown type {:?}", ext)));
+            }
+        }
+        let mut seen = vec![];
+        self.extensions.retain(|ext| if seen.contains(ext) { false } else { seen.push(ext.clone()); true });
+
+        Ok(())
+    }
 }
 
+/// Builds an [`Opts`], starting from the same defaults as the command-line utility.
+#[derive(Debug, Clone)]
+pub struct OptsBuilder {
+    opts: Opts,
+    is_cached: bool,
+}
+
+impl OptsBuilder {
+    /// The output directory for the resized images.
+    pub fn outpath<P: Into<PathBuf>>(mut self, outpath: P) -> Self {
+        self.opts.outpath = outpath.into();
+        self
+    }
+
+    /// String prefix to the filenames within the tag.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.opts.prefix = prefix.into();
+        self.opts.overrides.prefix = Some(self.opts.prefix.clone());
+        self
+    }
+
+    /// The output types (jpg, png, webp, ...) each size is encoded in. The last is the fallback
+    /// for the <img>, the others become <picture> sources. Empty keeps the type of the original.
+    pub fn extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.opts.extensions = extensions.into_iter()
+            .map(|ext| ext.as_ref().trim().to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        self.opts.overrides.formats = Some(self.opts.extensions.clone());
+        self
+    }
+
+    /// The widths of the responsive images.
+    pub fn sizes(mut self, sizes: Vec<u32>) -> Self {
+        self.opts.overrides.sizes = Some(sizes.clone());
+        self.opts.sizes = sizes;
+        self
+    }
+
+    /// Density mode: scale each image to the CSS display width times each of the densities,
+    /// described as `1x`, `2x` ... in the tag, rather than to the sizes.
+    pub fn density(mut self, css_width: u32) -> Self {
+        self.opts.density = Some(css_width);
+        self.opts.overrides.density = Some(css_width);
+        self
+    }
+
+    /// The pixel densities of density mode; defaults to 1, 2 and 3.
+    pub fn densities(mut self, densities: Vec<u32>) -> Self {
+        self.opts.overrides.densities = Some(densities.clone());
+        self.opts.densities = densities;
+        self
+    }
+
+    /// The `sizes` attribute of the tag, such as `"(min-width: 1024px) 50vw, 100vw"`.
+    pub fn sizes_attr<S: Into<String>>(mut self, sizes_attr: S) -> Self {
+        self.opts.sizes_attr = Some(sizes_attr.into());
+        self.opts.overrides.sizes_attr = self.opts.sizes_attr.clone();
+        self
+    }
+
+    /// The template of the markup, such as `Template::bundled("jsx")` or one loaded from a file.
+    pub fn template(mut self, template: Template) -> Self {
+        self.opts.overrides.template = Some(template.name().to_string());
+        self.opts.template = template;
+        self
+    }
+
+    /// Alt text by file, such as `AltMap::load("alt.csv")?`.
+    pub fn alts(mut self, alts: AltMap) -> Self {
+        self.opts.overrides.alt_csv = Some(alts.path().to_string_lossy().into_owned());
+        self.opts.alts = Some(Arc::new(alts));
+        self
+    }
+
+    /// The `loading` attribute of the <img>, `lazy` or `eager`.
+    pub fn loading<S: Into<String>>(mut self, loading: S) -> Self {
+        self.opts.loading = Some(loading.into());
+        self.opts.overrides.loading = self.opts.loading.clone();
+        self
+    }
+
+    /// The `decoding` attribute of the <img>, `async`, `sync` or `auto`.
+    pub fn decoding<S: Into<String>>(mut self, decoding: S) -> Self {
+        self.opts.decoding = Some(decoding.into());
+        self.opts.overrides.decoding = self.opts.decoding.clone();
+        self
+    }
+
+    /// The metadata of the source kept in each output: none, the copyright and author, or all.
+    pub fn metadata(mut self, metadata: Policy) -> Self {
+        self.opts.metadata = metadata;
+        self.opts.overrides.metadata = Some(format!("{:?}", metadata).to_lowercase());
+        self
+    }
+
+    /// Convert the pixels to sRGB, or keep them and embed the colour profile of the source.
+    pub fn icc(mut self, icc: IccPolicy) -> Self {
+        self.opts.icc = icc;
+        self.opts.overrides.icc = Some(format!("{:?}", icc).to_lowercase());
+        self
+    }
+
+    /// The art-directed crops, such as `"square=(max-width: 600px)".parse()?`.
+    pub fn crops(mut self, crops: Vec<Crop>) -> Self {
+        self.opts.overrides.crops = Some(crops.iter().map(Crop::to_string).collect());
+        self.opts.crops = crops;
+        self
+    }
+
+    /// Minimum size of an image to process, in kilobytes.
+    pub fn min_kb(mut self, min_kb: u64) -> Self {
+        self.opts.min_size = min_kb * 1024;
+        self.opts.overrides.min = Some(min_kb);
+        self
+    }
+
+    /// Encoder quality in the range 1-100.
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.opts.quality = quality;
+        self.opts.overrides.quality = Some(quality);
+        self
+    }
+
+    /// Encode WebP losslessly rather than at the quality.
+    pub fn lossless(mut self, yes: bool) -> Self {
+        self.opts.is_lossless = yes;
+        self.opts.overrides.lossless = Some(yes);
+        self
+    }
+
+    /// The AVIF encoder speed in the range 1-10, where 1 is the slowest and smallest.
+    pub fn avif_speed(mut self, speed: u8) -> Self {
+        self.opts.avif_speed = speed;
+        self.opts.overrides.avif_speed = Some(speed);
+        self
+    }
+
+    /// The most bytes a variant may take, searching down from the quality for the highest that fits.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.opts.max_bytes = Some(max_bytes);
+        self.opts.overrides.max_bytes = Some(max_bytes.to_string());
+        self
+    }
+
+    /// The byte budget of particular sizes, by width.
+    pub fn max_bytes_sizes(mut self, sizes: BTreeMap<u32, u64>) -> Self {
+        self.opts.overrides.max_bytes_sizes = Some(sizes.iter()
+            .map(|(width, max_bytes)| (width.to_string(), max_bytes.to_string()))
+            .collect());
+        self.opts.max_bytes_sizes = sizes;
+        self
+    }
+
+    /// Encode each variant at the lowest quality with an SSIM to its resized image of at least
+    /// `ssim`, such as 0.98.
+    pub fn ssim(mut self, ssim: f64) -> Self {
+        self.opts.ssim = Some(ssim);
+        self.opts.overrides.ssim = Some(ssim);
+        self
+    }
+
+    /// The resampling filter, such as `FilterType::Nearest` for pixel art.
+    pub fn filter(mut self, filter: FilterType) -> Self {
+        self.opts.filter = filter;
+        self.opts.overrides.filter = Some(filter_name(filter).to_string());
+        self
+    }
+
+    /// Unsharpen sigma and threshold.
+    pub fn unsharpen(mut self, sigma: f32, thresh: i32) -> Self {
+        self.opts.sigma = sigma;
+        self.opts.thresh = thresh;
+        self.opts.overrides.unsharpen = Some(format!("{},{}", sigma, thresh));
+        self
+    }
+
+    /// Sharpen each variant; on by default.
+    pub fn sharpen(mut self, yes: bool) -> Self {
+        self.opts.is_sharpen = yes;
+        self.opts.overrides.sharpen = Some(yes);
+        self
+    }
+
+    /// Scale the unsharpen sigma with the downscale ratio of each variant.
+    pub fn sharpen_by_ratio(mut self, yes: bool) -> Self {
+        self.opts.is_sharpen_by_ratio = yes;
+        self.opts.overrides.sharpen_by_ratio = Some(yes);
+        self
+    }
+
+    /// The unsharpen sigma and threshold of particular sizes, by width.
+    pub fn unsharpen_sizes(mut self, sizes: BTreeMap<u32, (f32, i32)>) -> Self {
+        self.opts.overrides.unsharpen_sizes = Some(sizes.iter()
+            .map(|(width, (sigma, thresh))| (width.to_string(), format!("{},{}", sigma, thresh)))
+            .collect());
+        self.opts.unsharpen_sizes = sizes;
+        self
+    }
+
+    /// Recurse the provided directory.
+    pub fn recurse(mut self, yes: bool) -> Self {
+        self.opts.is_recurse = yes;
+        self
+    }
 
+    /// Use parallel jobs.
+    pub fn jobs(mut self, yes: bool) -> Self {
+        self.opts.is_jobs = yes;
+        self
+    }
 
+    /// The number of parallel workers; zero uses one per core.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.opts.threads = threads;
+        self
+    }
 
-#[derive(Debug)]
+    /// Save the images in a nested hierarchy rather than flat.
+    pub fn nested(mut self, yes: bool) -> Self {
+        self.opts.is_nested = yes;
+        self.opts.overrides.nested = Some(yes);
+        self
+    }
+
+    /// Read `srcset.toml` files from the input root and its subdirectories; on by default.
+    pub fn config(mut self, yes: bool) -> Self {
+        self.opts.is_config = yes;
+        self
+    }
+
+    /// Skip images whose outputs are already up to date.
+    pub fn incremental(mut self, yes: bool) -> Self {
+        self.opts.is_incremental = yes;
+        self
+    }
+
+    /// Embed a short content hash in each output filename, for immutable caching.
+    pub fn hashed(mut self, yes: bool) -> Self {
+        self.opts.is_hashed = yes;
+        self
+    }
+
+    /// Keep a content-hash cache of the outputs in the outpath, loaded by `build`.
+    pub fn cache(mut self, yes: bool) -> Self {
+        self.is_cached = yes;
+        self
+    }
+
+    /// Test run; images are found but not created.
+    pub fn test(mut self, yes: bool) -> Self {
+        self.opts.is_test = yes;
+        self
+    }
+
+    /// Verbose output.
+    pub fn verbose(mut self, yes: bool) -> Self {
+        self.opts.is_verbose = yes;
+        self
+    }
+
+    /// Quiet the warnings.
+    pub fn quiet(mut self, yes: bool) -> Self {
+        self.opts.is_quiet = yes;
+        self
+    }
+
+    /// Write a `srcset.txt` tag file next to the images.
+    pub fn tagfile(mut self, yes: bool) -> Self {
+        self.opts.is_tagfile = yes;
+        self
+    }
+
+    /// Scale to the largest size regardless of the width of the original.
+    pub fn largest(mut self, yes: bool) -> Self {
+        self.opts.use_largest = yes;
+        self
+    }
+
+    /// Validate and return the options. A single file input disables nesting and recursion.
+    pub fn build(self) -> Result<Opts> {
+        let mut opts = self.opts;
+
+        if opts.inpath.as_os_str().is_empty() {
+            return Err(Error::InvalidOption("file or directory argument is required".to_string()));
+        }
+        if opts.outpath.is_file() {
+            return Err(Error::InvalidOption("selected outpath cannot be a file".to_string()));
+        }
+        opts.validate()?;
+
+        if self.is_cached {
+            opts.cache = Some(Arc::new(Cache::load(&opts.outpath)?));
+        }
+
+        if opts.inpath.is_file() {
+            opts.is_nested = false;
+            opts.is_recurse = false;
+            opts.is_file = true;
+        }
+
+        Ok(opts)
+    }
+}
+
+/// Parse the sizes from a comma,separated,value string such as `"320, 480, 640"`.
+pub fn parse_sizes(s: &str) -> Result<Vec<u32>> {
+    s.split(',')
+        .map(|x| x.trim().parse::<u32>()
+            .map_err(|_| Error::InvalidOption(format!("invalid size {:?}", x.trim()))))
+        .collect()
+}
+
+/// Parse the densities from a comma,separated,value string such as `"1x, 2x"` or `"1,2,3"`.
+pub fn parse_densities(s: &str) -> Result<Vec<u32>> {
+    s.split(',')
+        .map(|x| x.trim().trim_end_matches('x').parse::<u32>()
+            .map_err(|_| Error::InvalidOption(format!("invalid density {:?}", x.trim()))))
+        .collect()
+}
+
+/// Parse the output types from a comma,separated,value string such as `"webp,avif,jpg"`.
+pub fn parse_extensions(s: &str) -> Vec<String> {
+    s.split(',').map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty()).collect()
+}
+
+/// Parse the unsharpen sigma and threshold from a string such as `"0.25,8"`.
+pub fn parse_unsharpen(s: &str) -> Result<(f32, i32)> {
+    let invalid = || Error::InvalidOption(format!("invalid unsharpen {:?}", s));
+    let (sigma, thresh) = s.split_once(',').ok_or_else(invalid)?;
+    let sigma = sigma.trim().parse::<f32>().map_err(|_| invalid())?;
+    let thresh = thresh.trim().parse::<i32>().map_err(|_| invalid())?;
+    Ok((sigma, thresh))
+}
+
+/// Parse the unsharpen sigma and threshold of particular sizes from a string such as
+/// `"320=0.5,4;480=0.4,6"`.
+pub fn parse_unsharpen_sizes(s: &str) -> Result<BTreeMap<u32, (f32, i32)>> {
+    s.split(';').map(str::trim).filter(|e| !e.is_empty()).map(|entry| {
+        let (width, unsharpen) = entry.split_once('=')
+            .ok_or_else(|| Error::InvalidOption(format!("invalid unsharpen size {:?}, expected width=sigma,threshold", entry)))?;
+        let width = width.trim().parse::<u32>()
+            .map_err(|_| Error::InvalidOption(format!("invalid size {:?}", width.trim())))?;
+        Ok((width, parse_unsharpen(unsharpen)?))
+    }).collect()
+}
+
+/// Parse a number of bytes with an optional `k` or `m` suffix of 1024 bytes, such as `"80k"`.
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let invalid = || Error::InvalidOption(format!("invalid bytes {:?}, expected such as 80000, 80k or 1.5m", s));
+    let lower = s.trim().to_lowercase();
+    let (number, unit) = match lower.trim_end_matches('b') {
+        n if n.ends_with('k') => (&n[..n.len() - 1], 1024.0),
+        n if n.ends_with('m') => (&n[..n.len() - 1], 1024.0 * 1024.0),
+        n => (n, 1.0),
+    };
+    let number = number.trim().parse::<f64>().map_err(|_| invalid())?;
+    match number >= 0.0 {
+        true => Ok((number * unit).round() as u64),
+        false => Err(invalid()),
+    }
+}
+
+/// Parse the byte budget of particular sizes from a string such as `"320=20k;640=50k"`.
+pub fn parse_max_bytes_sizes(s: &str) -> Result<BTreeMap<u32, u64>> {
+    s.split(';').map(str::trim).filter(|e| !e.is_empty()).map(|entry| {
+        let (width, max_bytes) = entry.split_once('=')
+            .ok_or_else(|| Error::InvalidOption(format!("invalid max bytes size {:?}, expected width=bytes", entry)))?;
+        let width = width.trim().parse::<u32>()
+            .map_err(|_| Error::InvalidOption(format!("invalid size {:?}", width.trim())))?;
+        Ok((width, parse_bytes(max_bytes)?))
+    }).collect()
+}
+
+/// The resampling filters by name.
+const FILTERS: [(&str, FilterType); 5] = [
+    ("nearest", FilterType::Nearest), ("triangle", FilterType::Triangle), ("catmullrom", FilterType::CatmullRom),
+    ("gaussian", FilterType::Gaussian), ("lanczos3", FilterType::Lanczos3),
+];
+
+/// Parse a resampling filter by name, such as `lanczos3` or `nearest`.
+pub fn parse_filter(s: &str) -> Result<FilterType> {
+    let name = s.trim().to_lowercase().replace(['-', '_'], "");
+    FILTERS.iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, filter)| *filter)
+        .ok_or_else(|| Error::InvalidOption(format!("filter {:?} is not nearest, triangle, catmullrom, gaussian or lanczos3", s)))
+}
+
+/// The name of a resampling filter, as parsed by `parse_filter`.
+pub fn filter_name(filter: FilterType) -> &'static str {
+    FILTERS.iter().find(|(_, f)| *f == filter).map(|(n, _)| *n).unwrap_or("lanczos3")
+}
+
+
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Metrics {
     pub count: u32,
     pub resized: u32,
     pub traversed: u32,
-    pub skipped: u32
+    pub skipped: u32,
+    /// Images left alone by an incremental run.
+    pub up_to_date: u32,
+}
+
+impl Metrics {
+    /// Add the counts of another, such as those of one file processed on a worker.
+    pub fn merge(&mut self, other: &Metrics) {
+        self.count += other.count;
+        self.resized += other.resized;
+        self.traversed += other.traversed;
+        self.skipped += other.skipped;
+        self.up_to_date += other.up_to_date;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_a_zero_size() {
+        let mut opts = Opts { sizes: vec![0, 320], ..Opts::default() };
+        let err = opts.validate().unwrap_err().to_string();
+        assert!(err.contains("size 0"), "{}", err);
+        assert!(Opts { sizes: vec![320, 480], ..Opts::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn parses_bytes_with_units() {
+        assert_eq!(parse_bytes("80000").unwrap(), 80000);
+        assert_eq!(parse_bytes("80k").unwrap(), 80 * 1024);
+        assert_eq!(parse_bytes(" 80 KB ").unwrap(), 80 * 1024);
+        assert_eq!(parse_bytes("1.5m").unwrap(), 1536 * 1024);
+        assert_eq!(parse_bytes("1.5mb").unwrap(), 1536 * 1024);
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("-1k").is_err());
+        assert!(parse_bytes("80g").is_err());
+    }
+
+    #[test]
+    fn parses_max_bytes_by_size() {
+        let sizes = parse_max_bytes_sizes("320=20k; 640=50000;").unwrap();
+        assert_eq!(sizes, BTreeMap::from([(320, 20 * 1024), (640, 50000)]));
+        assert!(parse_max_bytes_sizes("").unwrap().is_empty());
+        assert!(parse_max_bytes_sizes("320").is_err());
+        assert!(parse_max_bytes_sizes("wide=20k").is_err());
+        assert!(parse_max_bytes_sizes("320=lots").is_err());
+    }
 }
@@ -0,0 +1,40 @@
+//! The EXIF Orientation of a source image, applied before resizing so that every variant is
+//! upright. Any EXIF or XMP kept by the `metadata` policy has its orientation reset to match.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::DynamicImage;
+
+/// The EXIF Orientation, 1 to 8, of the image; 1 (upright) if there is none.
+pub fn orientation(path: &Path) -> u32 {
+    let read = || -> Option<u32> {
+        let mut reader = BufReader::new(File::open(path).ok()?);
+        let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+    };
+    read().filter(|o| (1..=8).contains(o)).unwrap_or(1)
+}
+
+/// The width and height once upright; orientations 5 to 8 turn the image on its side.
+pub fn upright_dimensions(width: u32, height: u32, orientation: u32) -> (u32, u32) {
+    match orientation {
+        5..=8 => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// Rotate and flip the image upright.
+pub fn make_upright(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
@@ -0,0 +1,272 @@
+//! Checks a `sizes` attribute given by the user, such as `(min-width: 1024px) 50vw, 100vw`.
+//!
+//! The value is a comma separated list of a media condition and a length, the last of which
+//! usually has no condition. Browsers silently skip an entry they cannot parse, so a typo would
+//! only show up as images of the wrong size; here it is rejected before any image is written.
+
+use crate::error::{Error, Result};
+
+/// The units of a length in CSS Values 4 and Containment 3; a percentage is not allowed in `sizes`.
+const UNITS: &[&str] = &[
+    // Relative to the font
+    "em", "rem", "ex", "rex", "cap", "rcap", "ch", "rch", "ic", "ric", "lh", "rlh",
+    // Relative to the viewport, and to its small, large and dynamic sizes
+    "vw", "vh", "vi", "vb", "vmin", "vmax",
+    "svw", "svh", "svi", "svb", "svmin", "svmax",
+    "lvw", "lvh", "lvi", "lvb", "lvmin", "lvmax",
+    "dvw", "dvh", "dvi", "dvb", "dvmin", "dvmax",
+    // Relative to the query container
+    "cqw", "cqh", "cqi", "cqb", "cqmin", "cqmax",
+    // Absolute
+    "px", "cm", "mm", "q", "in", "pt", "pc",
+];
+
+/// The functions allowed as a length.
+const FUNCTIONS: [&str; 4] = ["calc", "min", "max", "clamp"];
+
+/// Check that `s` is a well formed `sizes` attribute.
+pub fn validate(s: &str) -> Result<()> {
+    let invalid = |why: String| Error::InvalidOption(format!("invalid sizes attribute {:?}: {}", s, why));
+
+    let entries = split_top(s, ',').map_err(invalid)?;
+    let last = entries.len() - 1;
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            return Err(invalid("empty entry".to_string()));
+        }
+
+        let mut tokens = tokens(entry);
+        let length = tokens.pop().unwrap_or_default();
+        if !is_length(&length) {
+            return Err(invalid(format!("{:?} is not a length such as 100vw or 320px", length)));
+        }
+
+        if tokens.is_empty() {
+            if i != last {
+                return Err(invalid(format!("{:?} has no media condition, so the entries after it are never used", entry)));
+            }
+        } else {
+            condition(&tokens).map_err(invalid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that `s` is a well formed media condition, or a comma separated list of them, such as
+/// the `media` of the `<source>` of a crop.
+pub fn validate_media(s: &str) -> Result<()> {
+    let invalid = |why: String| Error::InvalidOption(format!("invalid media condition {:?}: {}", s, why));
+
+    for query in split_top(s, ',').map_err(invalid)? {
+        let tokens = tokens(query);
+        if tokens.is_empty() {
+            return Err(invalid("empty condition".to_string()));
+        }
+        condition(&tokens).map_err(invalid)?;
+    }
+    Ok(())
+}
+
+/// Split on `sep` outside of any parentheses, failing if they do not balance.
+fn split_top(s: &str, sep: char) -> std::result::Result<Vec<&str>, String> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(format!("unexpected ')' at {}", i)),
+            ')' => depth -= 1,
+            c if c == sep && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            },
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return Err("missing ')'".to_string());
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+/// The whitespace separated tokens of an entry, a parenthesised group, with any function name
+/// before it, being one token. The parentheses are already known to balance.
+fn tokens(entry: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut depth = 0;
+    for c in entry.chars() {
+        match c {
+            '(' => { depth += 1; token.push(c); },
+            ')' => { depth -= 1; token.push(c); },
+            c if c.is_whitespace() && depth == 0 => {
+                if !token.is_empty() { tokens.push(std::mem::take(&mut token)); }
+            },
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() { tokens.push(token); }
+    tokens
+}
+
+/// Whether the token is a non-negative length, a CSS function or `auto`.
+fn is_length(token: &str) -> bool {
+    if token == "0" || token.eq_ignore_ascii_case("auto") {
+        return true;
+    }
+    if let Some(open) = token.find('(') {
+        return token.ends_with(')') && FUNCTIONS.contains(&token[..open].to_ascii_lowercase().as_str());
+    }
+
+    let unit_at = token.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(token.len());
+    let (number, unit) = token.split_at(unit_at);
+    number.parse::<f32>().is_ok() && UNITS.contains(&unit.to_ascii_lowercase().as_str())
+}
+
+/// Check a media condition: `not (a)`, `(a)`, `(a) and (b) ...` or `(a) or (b) ...`.
+fn condition(tokens: &[String]) -> std::result::Result<(), String> {
+    let (first, rest) = match tokens.first().map(String::as_str) {
+        Some(t) if t.eq_ignore_ascii_case("not") => return match tokens {
+            [_, group] => in_parens(group),
+            _ => Err("'not' takes a single parenthesised condition".to_string()),
+        },
+        Some(_) => (&tokens[0], &tokens[1..]),
+        None => return Ok(()),
+    };
+    in_parens(first)?;
+
+    if rest.len() % 2 != 0 {
+        return Err(format!("expected 'and' or 'or' between the conditions of {:?}", tokens.join(" ")));
+    }
+    let joiner = rest.first().map(|t| t.to_ascii_lowercase());
+    for pair in rest.chunks(2) {
+        let word = pair[0].to_ascii_lowercase();
+        if word != "and" && word != "or" {
+            return Err(format!("expected 'and' or 'or', found {:?}", pair[0]));
+        }
+        if Some(&word) != joiner.as_ref() {
+            return Err("'and' and 'or' cannot be mixed without parentheses".to_string());
+        }
+        in_parens(&pair[1])?;
+    }
+    Ok(())
+}
+
+/// Check a parenthesised media feature, such as `(min-width: 768px)` or `(width >= 768px)`,
+/// or a nested condition.
+fn in_parens(group: &str) -> std::result::Result<(), String> {
+    let inner = group.strip_prefix('(').and_then(|g| g.strip_suffix(')'))
+        .ok_or_else(|| format!("{:?} is not a parenthesised media condition", group))?
+        .trim();
+
+    if inner.starts_with('(') || inner.to_ascii_lowercase().starts_with("not ") {
+        return condition(&tokens(inner));
+    }
+
+    let is_name = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+    // A plain feature, `(name: value)` or the boolean `(name)`
+    if let Some((name, value)) = inner.split_once(':') {
+        return match is_name(name.trim()) && !value.trim().is_empty() {
+            true => Ok(()),
+            false => Err(format!("{:?} is not a media feature such as (min-width: 768px)", group)),
+        };
+    }
+    if is_name(inner) {
+        return Ok(());
+    }
+
+    // A range, such as `width >= 768px` or `480px < width <= 960px`
+    let parts: Vec<&str> = inner.split(['<', '>', '='])
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect();
+    match parts.len() {
+        2 | 3 if parts.iter().any(|p| is_name(p) && !is_length(p)) => Ok(()),
+        _ => Err(format!("{:?} is not a media feature such as (min-width: 768px)", group)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition_of(s: &str) -> std::result::Result<(), String> {
+        condition(&tokens(s))
+    }
+
+    #[test]
+    fn accepts_the_baseline_defaults() {
+        // The defaults picked by the width of the legacy image have no space after the colon
+        // and, mostly, a condition on every entry
+        for s in ["(max-width:480px) 100vw, (min-width:481px) 25vw",
+                  "(max-width:320px) 50vw, (max-width:768px) 100vw, (min-width:769px) 50vw",
+                  "(max-width:320px) 25vw, (min-width: 960px) 75vw, 100vw"] {
+            assert!(validate(s).is_ok(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn accepts_lengths_in_every_unit_and_function() {
+        assert!(validate("(min-width: 40em) 50dvw, (orientation: portrait) 80svh, 30cqi").is_ok());
+        assert!(validate("(min-width: 768px) calc(50vw - 2rem), min(100vw, 1200px)").is_ok());
+        assert!(validate("clamp(320px, 80vi, 60pc)").is_ok());
+        assert!(validate("100%").is_err());
+        assert!(validate("100vx").is_err());
+    }
+
+    #[test]
+    fn rejects_a_trailing_comma() {
+        assert!(validate("(min-width: 768px) 50vw, 100vw,").is_err());
+    }
+
+    #[test]
+    fn rejects_a_default_that_is_not_last() {
+        assert!(validate("100vw, (min-width: 768px) 50vw").is_err());
+    }
+
+    #[test]
+    fn checks_media_of_crops() {
+        assert!(validate_media("(max-width: 600px)").is_ok());
+        assert!(validate_media("(orientation: portrait), (max-width: 600px)").is_ok());
+        assert!(validate_media("").is_err());
+        assert!(validate_media("max-width: 600px").is_err());
+        assert!(validate_media("(max-width: 600px").is_err());
+        assert!(validate_media("(max-width: 600px),").is_err());
+    }
+
+    #[test]
+    fn splits_outside_of_parentheses() {
+        assert_eq!(split_top("(a, b) 1px, calc(1px, 2px)", ',').unwrap(), ["(a, b) 1px", " calc(1px, 2px)"]);
+        assert_eq!(split_top("100vw,", ',').unwrap(), ["100vw", ""]);
+        assert!(split_top("(min-width: 768px", ',').is_err());
+        assert!(split_top("min-width: 768px)", ',').is_err());
+    }
+
+    #[test]
+    fn checks_not_and_or() {
+        assert!(condition_of("not (hover)").is_ok());
+        assert!(condition_of("(min-width: 768px) and (orientation: landscape)").is_ok());
+        assert!(condition_of("(max-width: 320px) or (min-width: 1200px) or (print)").is_ok());
+        assert!(condition_of("((min-width: 768px) and (hover)) or (print)").is_ok());
+        assert!(condition_of("(min-width: 768px) and (hover) or (print)").is_err());
+        assert!(condition_of("not (hover) and (print)").is_err());
+        assert!(condition_of("(min-width: 768px) (hover)").is_err());
+        assert!(condition_of("(min-width: 768px) and").is_err());
+    }
+
+    #[test]
+    fn checks_features_and_ranges() {
+        assert!(in_parens("(min-width: 768px)").is_ok());
+        assert!(in_parens("(hover)").is_ok());
+        assert!(in_parens("(width >= 768px)").is_ok());
+        assert!(in_parens("(480px < width <= 960px)").is_ok());
+        assert!(in_parens("(not (hover))").is_ok());
+        assert!(in_parens("min-width: 768px").is_err());
+        assert!(in_parens("(min-width:)").is_err());
+        assert!(in_parens("(768px >= 480px)").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! The markup written for each image, rendered from a Handlebars template.
+//!
+//! The bundled templates are selected by name: `html` (the default), `jsx`, `markdown`, `liquid`,
+//! `jinja` and `vue`. Any other name is read as a template file. Each template is fed a [`TagData`];
+//! for example the `html` template is, without the line breaks,
+//!
+//! ```text
+//! {{#if sources}}<picture>{{#each sources}}<source{{#if media}} media="{{media}}"{{/if}} type="{{type}}" srcset="{{srcset}}"{{#if ../sizes}} sizes="{{../sizes}}"{{/if}}
+//! {{#if media}} width="{{width}}" height="{{height}}"{{/if}}>{{/each}}{{/if}}
+//! <img src="{{src}}" srcset="{{srcset}}"{{#if sizes}} sizes="{{sizes}}"{{/if}} width="{{width}}" height="{{height}}"
+//! {{#if loading}} loading="{{loading}}"{{/if}}{{#if decoding}} decoding="{{decoding}}"{{/if}} alt="{{alt}}">{{#if sources}}</picture>{{/if}}
+//! ```
+//!
+//! Values are escaped for where each template puts them: for a quoted HTML attribute in `html`,
+//! `jsx`, `vue` and any template file; with backslashes for the string literals of `liquid` and
+//! `jinja`, which the engine escapes for HTML itself; and with backslashes for the brackets and
+//! quotes of `markdown`. The `js` helper escapes a value for a single quoted JavaScript string
+//! first, as in the `:sources` expression of `vue`.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+const HTML: &str = "{{#if sources}}<picture>{{#each sources}}<source{{#if media}} media=\"{{media}}\"{{/if}} type=\"{{type}}\" srcset=\"{{srcset}}\"{{#if ../sizes}} sizes=\"{{../sizes}}\"{{/if}}\
+{{#if media}} width=\"{{width}}\" height=\"{{height}}\"{{/if}}>{{/each}}{{/if}}\
+<img src=\"{{src}}\" srcset=\"{{srcset}}\"{{#if sizes}} sizes=\"{{sizes}}\"{{/if}} \
+width=\"{{width}}\" height=\"{{height}}\"{{#if loading}} loading=\"{{loading}}\"{{/if}}{{#if decoding}} decoding=\"{{decoding}}\"{{/if}} alt=\"{{alt}}\">{{#if sources}}</picture>{{/if}}";
+
+const JSX: &str = "{{#if sources}}<picture>{{#each sources}}<source{{#if media}} media=\"{{media}}\"{{/if}} type=\"{{type}}\" srcSet=\"{{srcset}}\"{{#if ../sizes}} sizes=\"{{../sizes}}\"{{/if}}\
+{{#if media}} width=\"{{width}}\" height=\"{{height}}\"{{/if}} />{{/each}}{{/if}}\
+<img src=\"{{src}}\" srcSet=\"{{srcset}}\"{{#if sizes}} sizes=\"{{sizes}}\"{{/if}} \
+width=\"{{width}}\" height=\"{{height}}\"{{#if loading}} loading=\"{{loading}}\"{{/if}}{{#if decoding}} decoding=\"{{decoding}}\"{{/if}} alt=\"{{alt}}\" />{{#if sources}}</picture>{{/if}}";
+
+// Markdown has no <picture>, so only the fallback type and no crops; the space before the closing
+// brace keeps it apart from the `}}` of the block
+const MARKDOWN: &str = "![{{alt}}]({{src}}){srcset=\"{{srcset}}\"{{#if sizes}} sizes=\"{{sizes}}\"{{/if}} \
+width=\"{{width}}\" height=\"{{height}}\"{{#if loading}} loading=\"{{loading}}\"{{/if}}{{#if decoding}} decoding=\"{{decoding}}\"{{/if}} }";
+
+const LIQUID: &str = "{% include srcset.html src=\"{{src}}\" srcset=\"{{srcset}}\"{{#if sizes}} sizes=\"{{sizes}}\"{{/if}}\
+{{#each sources}}{{#unless media}} {{format}}_srcset=\"{{srcset}}\"{{/unless}}{{/each}} width=\"{{width}}\" height=\"{{height}}\"\
+{{#if loading}} loading=\"{{loading}}\"{{/if}}{{#if decoding}} decoding=\"{{decoding}}\"{{/if}} alt=\"{{alt}}\" %}";
+
+const JINJA: &str = "{% with src=\"{{src}}\", srcset=\"{{srcset}}\"{{#if sizes}}, sizes=\"{{sizes}}\"{{/if}}\
+{{#each sources}}{{#unless media}}, {{format}}_srcset=\"{{srcset}}\"{{/unless}}{{/each}}, width={{width}}, height={{height}}\
+{{#if loading}}, loading=\"{{loading}}\"{{/if}}{{#if decoding}}, decoding=\"{{decoding}}\"{{/if}}, alt=\"{{alt}}\" %}{% include \"srcset.html\" %}{% endwith %}";
+
+const VUE: &str = "<ResponsiveImage src=\"{{src}}\" srcset=\"{{srcset}}\"{{#if sizes}} sizes=\"{{sizes}}\"{{/if}} :width=\"{{width}}\" :height=\"{{height}}\"\
+{{#if loading}} loading=\"{{loading}}\"{{/if}}{{#if decoding}} decoding=\"{{decoding}}\"{{/if}} alt=\"{{alt}}\"\
+{{#if sources}} :sources=\"[{{#each sources}}{{#unless @first}}, {{/unless}}{ {{#if media}}media: '{{js media}}', {{/if}}type: '{{js type}}', srcset: '{{js srcset}}' }{{/each}}]\"{{/if}} />";
+
+/// The name the template is registered under in its registry.
+const TEMPLATE: &str = "tag";
+
+/// The bundled templates by name.
+pub const TEMPLATES: [(&str, &str); 6] = [
+    ("html", HTML), ("jsx", JSX), ("markdown", MARKDOWN), ("liquid", LIQUID), ("jinja", JINJA), ("vue", VUE),
+];
+
+/// A markup template, either bundled or read from a file, compiled once for every image.
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// The bundled name or the path it was read from.
+    name: String,
+    source: String,
+    registry: Arc<Handlebars<'static>>,
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Template) -> bool {
+        self.name == other.name && self.source == other.source
+    }
+}
+
+impl Eq for Template {}
+
+/// The data each template is rendered with.
+#[derive(Debug, Clone, Serialize)]
+pub struct TagData {
+    /// The directory (with any prefix) holding the images.
+    pub dir: String,
+    /// The original filename, without extension.
+    pub name: String,
+    pub alt: String,
+    /// The legacy image, for `src`.
+    pub src: String,
+    /// The intrinsic size for the `width` and `height` attributes: that of the legacy image, or in
+    /// density mode of the `1x` image.
+    pub width: u32,
+    pub height: u32,
+    /// The `loading` and `decoding` attributes, if any.
+    pub loading: Option<String>,
+    pub decoding: Option<String>,
+    /// The fallback type, such as `jpeg`, and its MIME type.
+    pub format: String,
+    #[serde(rename = "type")]
+    pub mime: String,
+    /// The srcset of the fallback type.
+    pub srcset: String,
+    /// The sizes attribute; none in density mode.
+    pub sizes: Option<String>,
+    /// A source for each type of each crop, then for each of the other types, the smallest
+    /// encodings first.
+    pub sources: Vec<TagSource>,
+    /// Every variant, of every type.
+    pub variants: Vec<TagVariant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TagSource {
+    pub format: String,
+    #[serde(rename = "type")]
+    pub mime: String,
+    pub srcset: String,
+    /// The media condition of a crop; none for the uncropped image.
+    pub media: Option<String>,
+    /// The intrinsic size, which differs from that of the <img> for a crop.
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TagVariant {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    pub format: String,
+    /// Such as `320w` or `2x`.
+    pub descriptor: String,
+    /// The crop it was cut from, if any.
+    pub crop: Option<String>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::bundled("html").unwrap()
+    }
+}
+
+impl Template {
+    /// The bundled template of the name.
+    pub fn bundled(name: &str) -> Option<Template> {
+        TEMPLATES.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(n, source)| Template::compile(n.to_string(), source.to_string(), escape_for(n)).expect("bundled templates compile"))
+    }
+
+    /// Read and compile a template file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Template> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| Error::Template(format!("{:?} is neither a bundled template nor a readable file: {}", path, e)))?;
+        Template::compile(path.to_string_lossy().into_owned(), source, escape_attr)
+            .map_err(|e| Error::Template(format!("{:?}: {}", path, e)))
+    }
+
+    /// Compile the source into a registry of its own, which escapes values with `escape`.
+    fn compile(name: String, source: String, escape: fn(&str) -> String) -> std::result::Result<Template, handlebars::TemplateError> {
+        let mut registry = Handlebars::new();
+        registry.register_escape_fn(escape);
+        registry.register_helper("js", Box::new(js));
+        registry.register_template_string(TEMPLATE, &source)?;
+        Ok(Template { name, source, registry: Arc::new(registry) })
+    }
+
+    /// The bundled template of the name, or else the template file at that path.
+    pub fn from_arg(s: &str) -> Result<Template> {
+        match Template::bundled(s.trim()) {
+            Some(template) => Ok(template),
+            None => Template::load(s.trim()),
+        }
+    }
+
+    /// The bundled name or the path of the file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The template text.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Render the markup of one image.
+    pub fn render(&self, data: &TagData) -> Result<String> {
+        self.registry.render(TEMPLATE, data)
+            .map_err(|e| Error::Template(format!("{}: {}", self.name, e)))
+    }
+}
+
+// The `js` helper, `{{js srcset}}`, for a value within a single quoted JavaScript string
+handlebars::handlebars_helper!(js: |s: str| escape_js(s));
+
+/// The escaping of the values of a bundled template.
+fn escape_for(name: &str) -> fn(&str) -> String {
+    match name {
+        "liquid" | "jinja" => escape_literal,
+        "markdown" => escape_markdown,
+        _ => escape_attr,
+    }
+}
+
+/// Escape a value for a double or single quoted attribute.
+fn escape_attr(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+/// Escape a value for a double quoted string literal of Liquid or Jinja.
+fn escape_literal(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape a value for Markdown, whether the alt text, the URL or a quoted attribute.
+fn escape_markdown(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '[' | ']' | '(' | ')' | '"') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape a value for a single quoted JavaScript string.
+fn escape_js(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALT: &str = r#"Tom & "Jerry's" [cat]"#;
+
+    fn render(name: &str) -> String {
+        let data = TagData {
+            dir: "it's".to_string(),
+            name: "cat".to_string(),
+            alt: ALT.to_string(),
+            src: "it's/legacy.jpg".to_string(),
+            width: 640,
+            height: 480,
+            loading: None,
+            decoding: None,
+            format: "jpeg".to_string(),
+            mime: "image/jpeg".to_string(),
+            srcset: "it's/320w.jpg 320w".to_string(),
+            sizes: None,
+            sources: vec![TagSource {
+                format: "jpeg".to_string(),
+                mime: "image/jpeg".to_string(),
+                srcset: "it's/square-320w.jpg 320w".to_string(),
+                media: Some("(min-width: 600px)".to_string()),
+                width: 320,
+                height: 320,
+            }],
+            variants: vec![],
+        };
+        Template::bundled(name).unwrap().render(&data).unwrap()
+    }
+
+    #[test]
+    fn escapes_html_attributes() {
+        for name in ["html", "jsx"] {
+            let tag = render(name);
+            assert!(tag.contains(r#"alt="Tom &amp; &quot;Jerry&#39;s&quot; [cat]""#), "{}", tag);
+            assert!(tag.contains(r#"src="it&#39;s/legacy.jpg""#), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn escapes_the_strings_of_a_vue_expression() {
+        let tag = render("vue");
+        assert!(tag.contains(r#"alt="Tom &amp; &quot;Jerry&#39;s&quot; [cat]""#), "{}", tag);
+        // Vue decodes the attribute first, leaving 'it\'s/square-320w.jpg 320w'
+        assert!(tag.contains(r"srcset: 'it\&#39;s/square-320w.jpg 320w'"), "{}", tag);
+    }
+
+    #[test]
+    fn escapes_liquid_and_jinja_literals_without_html() {
+        for name in ["liquid", "jinja"] {
+            let tag = render(name);
+            assert!(tag.contains(r#"alt="Tom & \"Jerry's\" [cat]""#), "{}", tag);
+            assert!(tag.contains(r#"src="it's/legacy.jpg""#), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn escapes_markdown_brackets() {
+        let tag = render("markdown");
+        assert!(tag.starts_with(r#"![Tom & \"Jerry's\" \[cat\]](it's/legacy.jpg){srcset="it's/320w.jpg 320w""#), "{}", tag);
+    }
+}
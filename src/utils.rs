@@ -1,9 +1,11 @@
-
-//! A few utilities for creating paths and directories. 
+//! A few utilities for creating paths and directories.
 
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
+use crate::error::{Error, Result};
+use crate::opts::Opts;
+
 /// Creates a std::path::Path from an array of strings
 #[inline]
 pub fn path_from_array(array: &[&str]) -> PathBuf {
@@ -17,18 +19,57 @@ pub fn path_from_array(array: &[&str]) -> PathBuf {
 /// Creates a full directory for the provided path, but drops the filename
 #[inline]
 pub fn mk_dir(p: &Path) {
-    match std::fs::create_dir_all(&p.parent().unwrap() ) {
-        Err(_) => (),
-        _ => (),
+    if let Some(parent) = p.parent() {
+        let _ = std::fs::create_dir_all(parent);
     }
 }
 
-/// Determine whether to use the filename extension or the provided str extension.
+/// Determine whether to use the filename extension or the provided extensions.
 #[inline]
-pub fn use_fileext<'a>(path: &'a Path, ext: &'a str) -> &'a str {
-    match ext
+pub fn use_fileexts<'a>(path: &'a Path, exts: &'a [String]) -> Result<Vec<&'a str>> {
+    match exts.is_empty()
     {
-        "" => path.extension().and_then(OsStr::to_str).unwrap(),
-        _ => ext,
+        true => Ok(vec![path.extension().and_then(OsStr::to_str).ok_or_else(|| Error::InvalidPath(path.to_path_buf()))?]),
+        _ => Ok(exts.iter().map(String::as_str).collect()),
+    }
+}
+
+/// The filename only with no extension.
+#[inline]
+pub fn file_stem(path: &Path) -> Result<&str> {
+    path.file_stem().and_then(OsStr::to_str).ok_or_else(|| Error::InvalidPath(path.to_path_buf()))
+}
+
+/// The directory of the provided path relative to the input path; used for nested output.
+#[inline]
+pub fn rel_parent<'a>(path: &'a Path, opts: &Opts) -> Result<&'a str> {
+    path.strip_prefix(opts.inpath.as_path()).ok()
+        .and_then(Path::parent)
+        .and_then(Path::to_str)
+        .ok_or_else(|| Error::InvalidPath(path.to_path_buf()))
+}
+
+/// The directory below `root` that holds every image generated from `path`, either
+/// flat or nested depending upon the options.
+pub fn image_dir(root: &str, path: &Path, opts: &Opts) -> Result<PathBuf> {
+    let file_name = file_stem(path)?;
+    match opts.is_nested {
+        true => Ok(path_from_array(&[root, rel_parent(path, opts)?, file_name])),
+        _ => Ok(path_from_array(&[root, file_name])),
+    }
+}
+
+/// Convert a path into a str, or fail with the offending path.
+#[inline]
+pub fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| Error::InvalidPath(path.to_path_buf()))
+}
+
+/// Whether every output exists and was modified no earlier than the source.
+pub fn is_fresh(source: &Path, outputs: &[&Path]) -> bool {
+    let modified = |p: &Path| p.metadata().and_then(|md| md.modified());
+    match modified(source) {
+        Ok(src) => outputs.iter().all(|o| modified(o).map(|t| t >= src).unwrap_or(false)),
+        Err(_) => false,
     }
 }
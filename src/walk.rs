@@ -1,28 +1,54 @@
-
 //! Walk a directory tree, hunting for jpg, png and tiff and image extensions.
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
-use anyhow::Result;
+use std::sync::{mpsc, Arc};
+use rayon::prelude::*;
 use yansi::Paint;
 
+use crate::config::dir_opts;
+use crate::error::{Error, Result};
 use crate::opts::{Opts, Metrics};
-use crate::img::process_image;
+use crate::img::{process_image, ProcessedImage};
+
+/// Walk or traverse a provided directory, then process every image found. Each image processed
+/// is handed to `on_image`, in the sorted order of the paths even when processed in parallel.
+pub fn walk_path(dir: &Path,  opts: &Opts, m: &mut Metrics, on_image: &mut dyn FnMut(&ProcessedImage)) -> Result<()>
+{
+    let paths = collect_paths(dir, opts, m)?;
+    process_paths(&paths, opts, m, on_image)
+}
+
+/// Collect the images found in the provided directory, sorted, each with the options that apply
+/// to it once any `srcset.toml` along the way is read. Calls recursively if a directory is found
+/// within the provided path, and the options specify todoso.
+pub fn collect_paths(dir: &Path, opts: &Opts, m: &mut Metrics) -> Result<Vec<(PathBuf, Arc<Opts>)>>
+{
+    let mut paths = vec![];
+    collect_into(dir, &Arc::new(opts.clone()), m, &mut paths)?;
+    paths.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(paths)
+}
 
-/// Walk or traverse a provided directory. Calls recursively if a directory is found within
-/// the provided path, and the options specify todoso.
-pub fn walk_path(dir: &Path,  opts: &Opts, m: &mut Metrics) -> Result<()>
+fn collect_into(dir: &Path, opts: &Arc<Opts>, m: &mut Metrics, paths: &mut Vec<(PathBuf, Arc<Opts>)>) -> Result<()>
 {
     if dir.is_dir() {
+        // A malformed srcset.toml stops the run before any image is written
+        let opts = match opts.is_config {
+            true => dir_opts(dir, opts)?.map(Arc::new).unwrap_or_else(|| opts.clone()),
+            false => opts.clone(),
+        };
+
          // An error here (permission denied) will bail the walk. Dont bail the walk. Instead continue back to the parent
         let rd = match std::fs::read_dir(dir) {
             Ok(t) => t,
             Err(e) => { if !opts.is_quiet{eprintln!("WARNING: Processing error {:?}", e);} return Ok(())},
         };
-        
+
         for entry in rd {
 
-            m.traversed = m.traversed + 1;
+            m.traversed += 1;
 
             let entry = match entry {
                 Ok(entry) => entry,
@@ -30,64 +56,118 @@ pub fn walk_path(dir: &Path,  opts: &Opts, m: &mut Metrics) -> Result<()>
             };
             let path = entry.path();
             if opts.is_recurse && path.is_dir() {
-                walk_path(&path, &opts, m)?;
-            } else {
-                digest_path(&path, &opts, m)?;
+                collect_into(&path, &opts, m, paths)?;
+            } else if is_image_path(&path, &opts, m) {
+                paths.push((path, opts.clone()));
             }
         }
     }
     Ok(())
 }
 
-/// Digest or consume a path. Check extension for image type (jpg, png, tif or others specified). In addition,
-/// Skips any filename matching `^\\d{3}w$|^\\d{4}w$|^legacy$`
-/// If matching the above concerns, then process the iamge.
+/// Process each of the paths with its options, across files on a pool of `opts.threads` workers when
+/// `opts.is_jobs`. Results are handed to `on_image` in the order of `paths` as soon as every earlier
+/// path is done.
+pub fn process_paths(paths: &[(PathBuf, Arc<Opts>)], opts: &Opts, m: &mut Metrics, on_image: &mut dyn FnMut(&ProcessedImage)) -> Result<()>
+{
+    if !opts.is_jobs {
+        for (path, path_opts) in paths {
+            report(path, process_image(path, path_opts, m), opts, on_image);
+        }
+        return Ok(());
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()
+        .map_err(|e| Error::InvalidOption(e.to_string()))?;
+
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::scope(|s| {
+        s.spawn(|| pool.install(||
+            paths.par_iter().enumerate().for_each_with(tx, |tx, (i, (path, path_opts))| {
+                let mut local = Metrics::default();
+                let r = process_image(path, path_opts, &mut local);
+                let _ = tx.send((i, local, r));
+            })));
+
+        // Hold back any result that finished ahead of an earlier path
+        let mut next = 0;
+        let mut pending = BTreeMap::new();
+        for (i, local, r) in rx {
+            pending.insert(i, (local, r));
+            while let Some((local, r)) = pending.remove(&next) {
+                m.merge(&local);
+                report(&paths[next].0, r, opts, on_image);
+                next += 1;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Hand a processed image to `on_image`, or warn of the error.
+fn report(path: &Path, r: Result<ProcessedImage>, opts: &Opts, on_image: &mut dyn FnMut(&ProcessedImage))
+{
+    match r {
+        Ok(processed) => on_image(&processed),
+        Err(e) => { if !opts.is_quiet{eprintln!("{} {:?}, {:?}", Paint::red("WARNING: Processing error: "), path, e)}},
+    }
+}
+
+/// Digest or consume a path. If it is an image as decided by `is_image_path` then process the image.
 /// Moves on without error if there is no match.
-pub fn digest_path(path: &Path, opts: &Opts, m: &mut Metrics) -> Result<()>
+pub fn digest_path(path: &Path, opts: &Opts, m: &mut Metrics, on_image: &mut dyn FnMut(&ProcessedImage)) -> Result<()>
+{
+    if is_image_path(path, opts, m) {
+        report(path, process_image(path, opts, m), opts, on_image);
+    }
+    Ok(())
+}
+
+/// Check extension for image type (jpg, png, tif or others specified) and the minimum size. In addition,
+/// Skips any filename matching `^(\\d{3}w|\\d{4}w|\\dx|legacy)(\\.[0-9a-f]{6})?$`, or the same sizes
+/// after the name of one of the crops, such as `square-320w`
+fn is_image_path(path: &Path, opts: &Opts, m: &mut Metrics) -> bool
 {
-    // match any filename with 3 or 4 digits ending in a w; and `legacy`
+    // match any filename with 3 or 4 digits ending in a w, or a density; and `legacy`. Either may carry a content hash
     lazy_static::lazy_static! {
-        static ref RE: regex::Regex = regex::Regex::new("^\\d{3}w$|^\\d{4}w$|^legacy$").unwrap();
+        static ref RE: regex::Regex = regex::Regex::new("^(\\d{3}w|\\d{4}w|\\dx|legacy)(\\.[0-9a-f]{6})?$").unwrap();
+        static ref SIZE: regex::Regex = regex::Regex::new("^(\\d{3}w|\\d{4}w|\\dx)(\\.[0-9a-f]{6})?$").unwrap();
     }
+    let is_variant = |nm: &str| RE.is_match(nm) || opts.crops.iter().any(|crop|
+        nm.strip_prefix(crop.name.as_str()).and_then(|n| n.strip_prefix('-')).is_some_and(|n| SIZE.is_match(n)));
 
     // Directories dont have extensions?! so will simply continue
     match path.extension().and_then(OsStr::to_str)
     {
-        // No extension. Move on
-        None => (),
-        
-        Some(s)
+        Some(s) if check_extension(s)
                 => {
-                    if check_extension(s) {
-                        if path.metadata().unwrap().len() > opts.min_size
-                        {
-                            let nm = path.file_stem().and_then(OsStr::to_str).unwrap();
-
-                            // Make sure were not converting a previously converted image. Matching the filename
-                            if !RE.is_match(nm)
-                            {
-                                match process_image(&path, &opts, m) {
-                                    //Err(e) => { if !opts.is_quiet{eprintln!("WARNING: Processing error {:?} {:?}", path, e)}},
-                                    Err(e) => { if !opts.is_quiet{eprintln!("{} {:?}, {:?}", Paint::red("WARNING: Processing error: "), path, e)}},                                    
-                                    _ => (),
-                                }
-                            }
-                        }
-                        else
-                        {
-                            m.skipped = m.skipped + 1;
-                            if !opts.is_quiet{eprintln!("WARNING: Skipping {:?}", path)};
-                        }
+                    if path.metadata().map(|md| md.len()).unwrap_or(0) > opts.min_size
+                    {
+                        let nm = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+
+                        // Make sure were not converting a previously converted image. Matching the filename
+                        !is_variant(nm)
                     }
+                    else
+                    {
+                        m.skipped += 1;
+                        if !opts.is_quiet{eprintln!("WARNING: Skipping {:?}", path)};
+                        false
                     }
-                    ,
-    }
+                    },
 
-    Ok(())
+        // No extension, or not an image. Move on
+        _ => false,
+    }
 }
 
 
-fn check_extension(ext: &str) -> bool
+/// Whether the extension is an image format srcset was built to decode.
+pub fn check_extension(ext: &str) -> bool
 {
     match ext.to_lowercase().as_str() {
         #[cfg(feature = "bmp")]
@@ -114,8 +194,8 @@ fn check_extension(ext: &str) -> bool
         "webp" => true,
         #[cfg(feature = "farbfeld")]
         "ff" => true,
-        #[cfg(any(feature = "avif-encoder", feature = "avif-decoder"))]
+        #[cfg(feature = "avif-decoder")]
         "avif" => true,
         _  => false,
     }
-}
\ No newline at end of file
+}